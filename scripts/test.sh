@@ -0,0 +1,9 @@
+#!/bin/bash
+
+# Runs the platform-independent core on the host. The ESP-IDF toolchain from
+# `rust-toolchain.toml` and the xtensa target from `.cargo/config.toml` are
+# overridden, and the `esp` feature is left out.
+
+HOST_TARGET=$(rustc +stable -vV | sed -n 's/^host: //p')
+
+cargo +stable test --lib --no-default-features --target "$HOST_TARGET" "$@"
@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+/// Asynchronous wait used by the hardware code, so it runs on the `EspAsyncTimer` on the device
+/// and on a simulated clock on the host.
+#[allow(async_fn_in_trait)]
+pub trait AsyncTimer {
+    async fn after(&mut self, duration: Duration) -> Result<()>;
+}
+
+#[cfg(feature = "esp")]
+impl AsyncTimer for esp_idf_svc::timer::EspAsyncTimer {
+    async fn after(&mut self, duration: Duration) -> Result<()> {
+        esp_idf_svc::timer::EspAsyncTimer::after(self, duration).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command_response::CommandError,
+    tpl_potentiometer::{wiper_step_to_resistance, MAX_WIPER_STEP},
+};
+
+/// Storage key of the charging calibration.
+pub const CALIBRATION_STORAGE_KEY: &str = "calibration";
+
+/// Voltage assumed at the charging output when nothing was measured.
+pub const EXPECTED_VOLTAGE: f32 = 4.5;
+
+/// Wiper steps sampled for the spec sheet default.
+const SPEC_SHEET_STEP_WIDTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(tag = "error", rename_all = "kebab-case")]
+pub enum CalibrationError {
+    InvalidVoltage,
+    TooFewPoints {
+        points: usize,
+    },
+    WiperStepOutOfRange {
+        wiper_step: u8,
+    },
+    InvalidCurrent {
+        wiper_step: u8,
+    },
+    /// Two points share a wiper step, or the current falls towards the higher one.
+    NotMonotonic {
+        wiper_step: u8,
+    },
+    /// The sweep measured the same current at every step, e.g. without a load connected.
+    NoCurrentChange,
+}
+
+impl CommandError for CalibrationError {
+    fn code(&self) -> u16 {
+        match self {
+            CalibrationError::InvalidVoltage => 1100,
+            CalibrationError::TooFewPoints { .. } => 1101,
+            CalibrationError::WiperStepOutOfRange { .. } => 1102,
+            CalibrationError::InvalidCurrent { .. } => 1103,
+            CalibrationError::NotMonotonic { .. } => 1104,
+            CalibrationError::NoCurrentChange => 1105,
+        }
+    }
+}
+
+impl Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::InvalidVoltage => write!(f, "Calibration voltage must be positive"),
+            CalibrationError::TooFewPoints { points } => {
+                write!(f, "Calibration needs at least two points, given: {points}")
+            }
+            CalibrationError::WiperStepOutOfRange { wiper_step } => write!(
+                f,
+                "Calibration wiper step {wiper_step} exceeds the potentiometer's range"
+            ),
+            CalibrationError::InvalidCurrent { wiper_step } => write!(
+                f,
+                "Calibration current at wiper step {wiper_step} must be a finite number"
+            ),
+            CalibrationError::NotMonotonic { wiper_step } => write!(
+                f,
+                "Calibration points must have distinct wiper steps and non-decreasing current, \
+                 wiper step {wiper_step} does not"
+            ),
+            CalibrationError::NoCurrentChange => write!(
+                f,
+                "Calibration sweep measured no change in current, is a load connected?"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CalibrationError {}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CalibrationPoint {
+    pub wiper_step: u8,
+    pub current_a: f32,
+}
+
+/// Piecewise-linear mapping between wiper steps of the TPL potentiometer and the charging
+/// current they produce. Points are sorted by wiper step and the current never decreases along
+/// them, so the mapping can be inverted.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(try_from = "ChargingCalibrationData")]
+pub struct ChargingCalibration {
+    voltage_v: f32,
+    points: Vec<CalibrationPoint>,
+}
+
+#[derive(Deserialize)]
+struct ChargingCalibrationData {
+    voltage_v: f32,
+    points: Vec<CalibrationPoint>,
+}
+
+impl TryFrom<ChargingCalibrationData> for ChargingCalibration {
+    type Error = CalibrationError;
+
+    fn try_from(data: ChargingCalibrationData) -> Result<Self, CalibrationError> {
+        ChargingCalibration::new(data.voltage_v, data.points)
+    }
+}
+
+impl Default for ChargingCalibration {
+    /// Data approximation of spec sheet: I = 901.07 / R^(0.99), with R in Ohm and I in A.
+    fn default() -> Self {
+        let points = (0..MAX_WIPER_STEP)
+            .step_by(SPEC_SHEET_STEP_WIDTH)
+            .chain([MAX_WIPER_STEP - 1])
+            .map(|wiper_step| {
+                let resistance_ohm = wiper_step_to_resistance(wiper_step) * 1000.0;
+                CalibrationPoint {
+                    wiper_step,
+                    current_a: 901.07 / resistance_ohm.powf(0.99),
+                }
+            })
+            .collect();
+        ChargingCalibration::new(EXPECTED_VOLTAGE, points)
+            .expect("Spec sheet calibration is monotonic")
+    }
+}
+
+impl ChargingCalibration {
+    pub fn new(
+        voltage_v: f32,
+        mut points: Vec<CalibrationPoint>,
+    ) -> Result<Self, CalibrationError> {
+        points.sort_by_key(|point| point.wiper_step);
+        if !voltage_v.is_finite() || voltage_v <= 0.0 {
+            Err(CalibrationError::InvalidVoltage)?
+        } else if points.len() < 2 {
+            Err(CalibrationError::TooFewPoints {
+                points: points.len(),
+            })?
+        }
+        for point in &points {
+            if point.wiper_step > MAX_WIPER_STEP {
+                Err(CalibrationError::WiperStepOutOfRange {
+                    wiper_step: point.wiper_step,
+                })?
+            } else if !point.current_a.is_finite() {
+                Err(CalibrationError::InvalidCurrent {
+                    wiper_step: point.wiper_step,
+                })?
+            }
+        }
+        if let Some(pair) = points.windows(2).find(|pair| {
+            pair[0].wiper_step == pair[1].wiper_step || pair[0].current_a > pair[1].current_a
+        }) {
+            Err(CalibrationError::NotMonotonic {
+                wiper_step: pair[1].wiper_step,
+            })?
+        }
+        Ok(ChargingCalibration { voltage_v, points })
+    }
+
+    /// Closest wiper step delivering `charging_speed_w`, clamped to the calibrated range.
+    pub fn wiper_step_for_power(&self, charging_speed_w: f32) -> u8 {
+        let current_a = charging_speed_w / self.voltage_v;
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if current_a <= first.current_a {
+            return first.wiper_step;
+        } else if current_a >= last.current_a {
+            return last.wiper_step;
+        }
+        let segment = self
+            .points
+            .windows(2)
+            .find(|pair| current_a <= pair[1].current_a)
+            .expect("Current lies within the calibrated range");
+        let (lower, upper) = (segment[0], segment[1]);
+        let fraction = (current_a - lower.current_a) / (upper.current_a - lower.current_a);
+        let steps = (upper.wiper_step - lower.wiper_step) as f32;
+        lower.wiper_step + (fraction * steps).round() as u8
+    }
+
+    /// Power delivered at `wiper_step`, clamped to the calibrated range.
+    pub fn power_for_wiper_step(&self, wiper_step: u8) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        let current_a = if wiper_step <= first.wiper_step {
+            first.current_a
+        } else if wiper_step >= last.wiper_step {
+            last.current_a
+        } else {
+            let segment = self
+                .points
+                .windows(2)
+                .find(|pair| wiper_step <= pair[1].wiper_step)
+                .expect("Wiper step lies within the calibrated range");
+            let (lower, upper) = (segment[0], segment[1]);
+            let fraction = (wiper_step - lower.wiper_step) as f32
+                / (upper.wiper_step - lower.wiper_step) as f32;
+            lower.current_a + fraction * (upper.current_a - lower.current_a)
+        };
+        current_a * self.voltage_v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(wiper_step: u8, current_a: f32) -> CalibrationPoint {
+        CalibrationPoint {
+            wiper_step,
+            current_a,
+        }
+    }
+
+    /// 2 V, 0.5 A at step 10 rising to 1.5 A at step 30 and 2.5 A at step 50.
+    fn calibration() -> ChargingCalibration {
+        ChargingCalibration::new(2.0, vec![point(30, 1.5), point(10, 0.5), point(50, 2.5)]).unwrap()
+    }
+
+    #[test]
+    fn interpolates_power_between_points() {
+        let calibration = calibration();
+        for (wiper_step, power_w) in [
+            (0, 1.0),
+            (10, 1.0),
+            (20, 2.0),
+            (25, 2.5),
+            (30, 3.0),
+            (40, 4.0),
+            (50, 5.0),
+            (MAX_WIPER_STEP, 5.0),
+        ] {
+            assert_eq!(
+                calibration.power_for_wiper_step(wiper_step),
+                power_w,
+                "step {wiper_step}"
+            );
+        }
+    }
+
+    #[test]
+    fn inverts_to_closest_wiper_step() {
+        let calibration = calibration();
+        for (power_w, wiper_step) in [
+            (0.0, 10),
+            (1.0, 10),
+            (2.0, 20),
+            (2.04, 20),
+            (2.06, 21),
+            (3.0, 30),
+            (4.5, 45),
+            (5.0, 50),
+            (100.0, 50),
+        ] {
+            assert_eq!(
+                calibration.wiper_step_for_power(power_w),
+                wiper_step,
+                "{power_w}w"
+            );
+        }
+        for wiper_step in 10..=50 {
+            assert_eq!(
+                calibration.wiper_step_for_power(calibration.power_for_wiper_step(wiper_step)),
+                wiper_step
+            );
+        }
+    }
+
+    #[test]
+    fn spec_sheet_default_ends_short_of_last_step() {
+        let calibration = ChargingCalibration::default();
+        assert_eq!(calibration.points[0].wiper_step, 0);
+        assert_eq!(calibration.wiper_step_for_power(0.0), 0);
+        assert_eq!(
+            calibration.wiper_step_for_power(f32::MAX),
+            MAX_WIPER_STEP - 1
+        );
+    }
+
+    #[test]
+    fn rejects_unusable_calibrations() {
+        let valid = || vec![point(0, 0.1), point(10, 0.5)];
+        for (voltage_v, points, error) in [
+            (0.0, valid(), CalibrationError::InvalidVoltage),
+            (-1.0, valid(), CalibrationError::InvalidVoltage),
+            (f32::NAN, valid(), CalibrationError::InvalidVoltage),
+            (
+                4.5,
+                vec![point(0, 0.1)],
+                CalibrationError::TooFewPoints { points: 1 },
+            ),
+            (
+                4.5,
+                vec![point(0, 0.1), point(MAX_WIPER_STEP + 1, 0.5)],
+                CalibrationError::WiperStepOutOfRange {
+                    wiper_step: MAX_WIPER_STEP + 1,
+                },
+            ),
+            (
+                4.5,
+                vec![point(0, 0.1), point(10, f32::INFINITY)],
+                CalibrationError::InvalidCurrent { wiper_step: 10 },
+            ),
+            (
+                4.5,
+                vec![point(0, 0.1), point(10, 0.5), point(10, 0.6)],
+                CalibrationError::NotMonotonic { wiper_step: 10 },
+            ),
+            (
+                4.5,
+                vec![point(0, 0.1), point(10, 0.5), point(20, 0.4)],
+                CalibrationError::NotMonotonic { wiper_step: 20 },
+            ),
+        ] {
+            assert_eq!(
+                ChargingCalibration::new(voltage_v, points.clone()),
+                Err(error),
+                "{voltage_v}V {points:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn deserializes_checked() {
+        let calibration: ChargingCalibration = serde_json::from_str(
+            r#"{"voltage_v":2.0,"points":[{"wiper_step":50,"current_a":2.5},{"wiper_step":10,"current_a":0.5},{"wiper_step":30,"current_a":1.5}]}"#,
+        )
+        .unwrap();
+        assert_eq!(calibration, self::calibration());
+        let error = serde_json::from_str::<ChargingCalibration>(
+            r#"{"voltage_v":2.0,"points":[{"wiper_step":10,"current_a":0.5}]}"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("at least two points"));
+    }
+}
@@ -0,0 +1,247 @@
+use std::{fmt::Debug, time::Duration};
+
+use anyhow::Result;
+use embedded_hal::{blocking::i2c, digital::v2::OutputPin};
+use ina219::INA219;
+use log::info;
+
+use crate::{
+    async_timer::AsyncTimer,
+    calibration::{
+        CalibrationError, CalibrationPoint, ChargingCalibration, CALIBRATION_STORAGE_KEY,
+    },
+    context::Context,
+    hardware_controller::HardwareController,
+    i2c::build_ina_stats,
+    mqtt::{MqttPublisher, QoS},
+    storage::store_json,
+    tpl_potentiometer::MAX_WIPER_STEP,
+};
+
+pub const CALIBRATION_TOPIC: &str = "calibration";
+
+/// Wiper steps between two measured points of the sweep.
+const CALIBRATION_STEP_WIDTH: usize = 4;
+/// Time the charging output gets to settle after each wiper step before it is measured.
+const CALIBRATION_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Wiper step with the highest resistance and so the lowest charging current.
+const LOWEST_CURRENT_WIPER_STEP: u8 = 0;
+
+/// Steps the TPL potentiometer through its range and measures current and bus voltage at the
+/// power INA219 for each step. Like the spec sheet default it stops short of the last step,
+/// which shorts the potentiometer, and leaves the wiper at the lowest current afterwards.
+pub async fn sweep_calibration<I2C, E, P>(
+    hardware_controller: &HardwareController<I2C, P>,
+    power_ina_219: &mut INA219<I2C>,
+    timer: &mut impl AsyncTimer,
+) -> Result<ChargingCalibration>
+where
+    I2C: i2c::Write<Error = E> + i2c::Read<Error = E>,
+    E: Debug,
+    P: OutputPin,
+    P::Error: Debug,
+{
+    info!("Calibration sweep started");
+    let calibration = measure_calibration(hardware_controller, power_ina_219, timer).await;
+    let reset = hardware_controller.set_wiper_step(LOWEST_CURRENT_WIPER_STEP);
+    let calibration = calibration?;
+    reset?;
+    info!("Calibration sweep finished");
+    Ok(calibration)
+}
+
+async fn measure_calibration<I2C, E, P>(
+    hardware_controller: &HardwareController<I2C, P>,
+    power_ina_219: &mut INA219<I2C>,
+    timer: &mut impl AsyncTimer,
+) -> Result<ChargingCalibration>
+where
+    I2C: i2c::Write<Error = E> + i2c::Read<Error = E>,
+    E: Debug,
+    P: OutputPin,
+    P::Error: Debug,
+{
+    let mut points: Vec<CalibrationPoint> = Vec::new();
+    let mut bus_voltage_sum_mv = 0.0;
+    for wiper_step in (0..MAX_WIPER_STEP - 1)
+        .step_by(CALIBRATION_STEP_WIDTH)
+        .chain([MAX_WIPER_STEP - 1])
+    {
+        hardware_controller.set_wiper_step_verified(wiper_step)?;
+        timer.after(CALIBRATION_SETTLE_TIME).await?;
+        let stats = build_ina_stats(power_ina_219)?;
+        info!("Wiper step {wiper_step}: {:?}", stats);
+        // Measurement noise must not make the curve fall, or it could not be inverted.
+        let previous_current_a = points.last().map_or(0.0, |point| point.current_a);
+        points.push(CalibrationPoint {
+            wiper_step,
+            current_a: stats.current.max(previous_current_a),
+        });
+        bus_voltage_sum_mv += stats.bus_voltage as f32;
+    }
+    let voltage_v = bus_voltage_sum_mv / points.len() as f32 / 1000.0;
+    if points[points.len() - 1].current_a <= points[0].current_a {
+        Err(CalibrationError::NoCurrentChange)?
+    }
+    Ok(ChargingCalibration::new(voltage_v, points)?)
+}
+
+/// Runs the sweep, stores and applies the resulting calibration and publishes it.
+pub async fn calibrate<I2C, E, P>(
+    context: &Context,
+    hardware_controller: &HardwareController<I2C, P>,
+    power_ina_219: &mut INA219<I2C>,
+    timer: &mut impl AsyncTimer,
+    mqtt_client: &mut impl MqttPublisher,
+) -> Result<()>
+where
+    I2C: i2c::Write<Error = E> + i2c::Read<Error = E>,
+    E: Debug,
+    P: OutputPin,
+    P::Error: Debug,
+{
+    let calibration = sweep_calibration(hardware_controller, power_ina_219, timer).await?;
+    {
+        let mut storage = context
+            .storage_mutex
+            .lock()
+            .expect("Failed lock on storage_mutex");
+        store_json(&mut *storage, CALIBRATION_STORAGE_KEY, &calibration)?;
+    }
+    let calibration_json = serde_json::to_string(&calibration)?;
+    *context
+        .calibration_rwlock
+        .write()
+        .expect("Failed write access on calibration_rwlock") = calibration;
+    mqtt_client
+        .publish(
+            CALIBRATION_TOPIC,
+            QoS::AtMostOnce,
+            false,
+            calibration_json.as_bytes(),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::Ordering, Arc, RwLock};
+
+    use super::*;
+    use crate::{
+        car::Car,
+        charging_error::ChargingError,
+        handler_functions::{handle_charge_for_departure, handle_start_charging},
+        i2c::{CURRENT_LSB, POWER_INA_219_ADDRESS, TPL_ADDRESS},
+        i2c_simulator::{
+            INA219Register, SimulatedI2CBus, SimulatedINA219, SimulatedTPLPotentiometer,
+        },
+        mock_hardware::{MockClock, MockTimer, RecordingOutputPin},
+        tpl_potentiometer::TPLPotentiometer,
+    };
+
+    /// Charging output whose current rises by `raw_current_per_step` with every wiper step, at
+    /// a bus voltage of 5 V.
+    fn simulated_output(
+        raw_current_per_step: u16,
+    ) -> (
+        HardwareController<SimulatedI2CBus, RecordingOutputPin>,
+        INA219<SimulatedI2CBus>,
+        SimulatedTPLPotentiometer,
+    ) {
+        let bus = SimulatedI2CBus::new();
+        let power_ina_219 = SimulatedINA219::new();
+        let tpl_potentiometer = SimulatedTPLPotentiometer::new();
+        bus.attach(POWER_INA_219_ADDRESS, power_ina_219.clone());
+        bus.attach(TPL_ADDRESS, tpl_potentiometer.clone());
+        power_ina_219.set_bus_voltage_mv(5000);
+        let wiper = tpl_potentiometer.clone();
+        power_ina_219.set_register_source(INA219Register::Current, move || {
+            wiper.wiper_step() as u16 * raw_current_per_step
+        });
+        let hardware_controller = HardwareController::new(
+            TPLPotentiometer::new(bus.clone(), TPL_ADDRESS),
+            RecordingOutputPin::new(MockClock::new()),
+            Default::default(),
+        );
+        (
+            hardware_controller,
+            INA219::new(bus, POWER_INA_219_ADDRESS),
+            tpl_potentiometer,
+        )
+    }
+
+    #[test]
+    fn sweep_measures_each_step_short_of_the_last() {
+        let (hardware_controller, mut power_ina_219, tpl_potentiometer) = simulated_output(100);
+        let mut timer = MockTimer::new(MockClock::new());
+
+        let calibration = embassy_futures::block_on(sweep_calibration(
+            &hardware_controller,
+            &mut power_ina_219,
+            &mut timer,
+        ))
+        .unwrap();
+        let mut expected_steps: Vec<u8> = (0..MAX_WIPER_STEP - 1).step_by(4).collect();
+        expected_steps.push(MAX_WIPER_STEP - 1);
+        expected_steps.push(LOWEST_CURRENT_WIPER_STEP);
+        assert_eq!(tpl_potentiometer.writes(), expected_steps);
+        assert_eq!(tpl_potentiometer.wiper_step(), LOWEST_CURRENT_WIPER_STEP);
+        assert_eq!(timer.waits(), vec![CALIBRATION_SETTLE_TIME; 33]);
+
+        let watts_per_step = 100.0 * *CURRENT_LSB * 5.0;
+        assert!((calibration.power_for_wiper_step(64) - 64.0 * watts_per_step).abs() < 1e-3);
+        assert_eq!(
+            calibration.power_for_wiper_step(MAX_WIPER_STEP),
+            calibration.power_for_wiper_step(MAX_WIPER_STEP - 1)
+        );
+        assert_eq!(calibration.wiper_step_for_power(64.0 * watts_per_step), 64);
+    }
+
+    #[test]
+    fn failed_sweep_leaves_lowest_current() {
+        let (hardware_controller, mut power_ina_219, tpl_potentiometer) = simulated_output(0);
+        let mut timer = MockTimer::new(MockClock::new());
+
+        assert!(embassy_futures::block_on(sweep_calibration(
+            &hardware_controller,
+            &mut power_ina_219,
+            &mut timer,
+        ))
+        .is_err());
+        assert_eq!(tpl_potentiometer.wiper_step(), LOWEST_CURRENT_WIPER_STEP);
+    }
+
+    #[test]
+    fn charging_waits_for_requested_sweep() {
+        let context = Context::in_memory();
+        context
+            .charging_controller_mutex
+            .lock()
+            .unwrap()
+            .connect_car(Arc::new(RwLock::new(Car::new(1000, 0, 100).unwrap())))
+            .unwrap();
+        context.calibration_requested.store(true, Ordering::SeqCst);
+
+        let error = handle_start_charging(
+            serde_json::from_str(r#"{"charging_speed_w":50}"#).unwrap(),
+            context.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ChargingError>(),
+            Some(&ChargingError::ChargeWhileCalibrating)
+        );
+        let error = handle_charge_for_departure(
+            serde_json::from_str(r#"{"target_charge_wh":900,"departure":"07:30"}"#).unwrap(),
+            context.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ChargingError>(),
+            Some(&ChargingError::ChargeWhileCalibrating)
+        );
+    }
+}
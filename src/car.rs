@@ -1,17 +1,41 @@
-use std::{
-    io::{Error, ErrorKind},
-};
+use serde::{Deserialize, Serialize};
 
-use anyhow::Result;
+use crate::charging_error::ChargingError;
 
-/// Defines when the battery is counted as full.
+/// Defines when the battery is counted as full if the car has no charge limit.
 static FULL_CAPACITY_MARGIN: u32 = 10;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "CarData")]
 pub struct Car {
     charging_capacity_wh: u32,
     current_charge_wh: u32,
     pub max_charging_speed_w: u32,
+    /// Charge the battery is counted as full at, to spare it the top of its capacity.
+    charge_limit_wh: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct CarData {
+    charging_capacity_wh: u32,
+    current_charge_wh: u32,
+    max_charging_speed_w: u32,
+    #[serde(default)]
+    charge_limit_wh: Option<u32>,
+}
+
+impl TryFrom<CarData> for Car {
+    type Error = ChargingError;
+
+    fn try_from(data: CarData) -> Result<Self, ChargingError> {
+        let mut car = Car::new(
+            data.charging_capacity_wh,
+            data.current_charge_wh,
+            data.max_charging_speed_w,
+        )?;
+        car.set_charge_limit(data.charge_limit_wh)?;
+        Ok(car)
+    }
 }
 
 impl Car {
@@ -19,34 +43,145 @@ impl Car {
         charging_capacity_wh: u32,
         current_charge_wh: u32,
         max_charging_speed_w: u32,
-    ) -> Result<Self> {
+    ) -> Result<Self, ChargingError> {
         if current_charge_wh > charging_capacity_wh {
-            Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Current charge cannot exceed charging capacity",
-            ))?
+            Err(ChargingError::ChargeExceedsCapacity {
+                charge_wh: current_charge_wh,
+                charging_capacity_wh,
+            })
         } else {
             Ok(Car {
                 charging_capacity_wh,
                 current_charge_wh,
                 max_charging_speed_w,
+                charge_limit_wh: None,
             })
         }
     }
 
+    pub fn charging_capacity_wh(&self) -> u32 {
+        self.charging_capacity_wh
+    }
+
+    pub fn current_charge_wh(&self) -> u32 {
+        self.current_charge_wh
+    }
+
+    /// Charge the battery is counted as full at, the charge limit if one is set.
+    pub fn full_charge_wh(&self) -> u32 {
+        self.charge_limit_wh
+            .unwrap_or(self.capacity_full_charge_wh())
+    }
+
+    /// Whether the charge limit ends charging before the battery would count as full.
+    pub fn is_charge_limited(&self) -> bool {
+        self.charge_limit_wh
+            .is_some_and(|charge_limit_wh| charge_limit_wh < self.capacity_full_charge_wh())
+    }
+
+    fn capacity_full_charge_wh(&self) -> u32 {
+        self.charging_capacity_wh
+            .saturating_sub(FULL_CAPACITY_MARGIN)
+    }
+
     pub fn is_fully_charged(&self) -> bool {
-        self.current_charge_wh >= self.charging_capacity_wh - FULL_CAPACITY_MARGIN
+        self.current_charge_wh >= self.full_charge_wh()
+    }
+
+    pub fn charge_limit_wh(&self) -> Option<u32> {
+        self.charge_limit_wh
+    }
+
+    /// Sets the charge limit, `None` charges to capacity.
+    pub fn set_charge_limit(&mut self, charge_limit_wh: Option<u32>) -> Result<(), ChargingError> {
+        match charge_limit_wh {
+            Some(charge_limit_wh) if charge_limit_wh > self.charging_capacity_wh => {
+                Err(ChargingError::ChargeExceedsCapacity {
+                    charge_wh: charge_limit_wh,
+                    charging_capacity_wh: self.charging_capacity_wh,
+                })
+            }
+            _ => {
+                self.charge_limit_wh = charge_limit_wh;
+                Ok(())
+            }
+        }
     }
 
-    pub fn change_current_charge(&mut self, new_charge_wh: u32) -> Result<()> {
+    pub fn change_current_charge(&mut self, new_charge_wh: u32) -> Result<(), ChargingError> {
         if new_charge_wh > self.charging_capacity_wh {
-            Err(Error::new(
-                ErrorKind::InvalidInput,
-                "New charge cannot exceed charging capacity",
-            ))?
+            Err(ChargingError::ChargeExceedsCapacity {
+                charge_wh: new_charge_wh,
+                charging_capacity_wh: self.charging_capacity_wh,
+            })?
         } else {
             self.current_charge_wh = new_charge_wh;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_charge_above_capacity() {
+        assert_eq!(
+            Car::new(1000, 1001, 100),
+            Err(ChargingError::ChargeExceedsCapacity {
+                charge_wh: 1001,
+                charging_capacity_wh: 1000,
+            })
+        );
+        let mut car = Car::new(1000, 0, 100).unwrap();
+        assert!(car.change_current_charge(1001).is_err());
+        assert_eq!(car.current_charge_wh(), 0);
+        car.change_current_charge(1000).unwrap();
+        assert_eq!(car.current_charge_wh(), 1000);
+    }
+
+    #[test]
+    fn full_within_margin_of_capacity() {
+        let mut car = Car::new(1000, 989, 100).unwrap();
+        assert_eq!(car.full_charge_wh(), 990);
+        assert!(!car.is_fully_charged());
+        car.change_current_charge(990).unwrap();
+        assert!(car.is_fully_charged());
+    }
+
+    #[test]
+    fn full_at_charge_limit() {
+        let mut car = Car::new(1000, 500, 100).unwrap();
+        car.set_charge_limit(Some(500)).unwrap();
+        assert_eq!(car.full_charge_wh(), 500);
+        assert!(car.is_fully_charged());
+        assert!(car.is_charge_limited());
+        assert!(car.set_charge_limit(Some(1001)).is_err());
+        assert_eq!(car.charge_limit_wh(), Some(500));
+        car.set_charge_limit(None).unwrap();
+        assert!(!car.is_fully_charged());
+    }
+
+    #[test]
+    fn limit_above_margin_does_not_limit() {
+        let mut car = Car::new(1000, 0, 100).unwrap();
+        car.set_charge_limit(Some(990)).unwrap();
+        assert!(!car.is_charge_limited());
+        car.set_charge_limit(Some(989)).unwrap();
+        assert!(car.is_charge_limited());
+    }
+
+    #[test]
+    fn deserializes_checked() {
+        let car: Car = serde_json::from_str(
+            r#"{"charging_capacity_wh":1000,"current_charge_wh":10,"max_charging_speed_w":100}"#,
+        )
+        .unwrap();
+        assert_eq!(car, Car::new(1000, 10, 100).unwrap());
+        assert!(serde_json::from_str::<Car>(
+            r#"{"charging_capacity_wh":1000,"current_charge_wh":1010,"max_charging_speed_w":100}"#,
+        )
+        .is_err());
+    }
+}
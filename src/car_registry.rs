@@ -0,0 +1,408 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    car::Car,
+    charging_controller::ChargingController,
+    command_response::CommandError,
+    storage::{load_json, store_json, Storage},
+};
+
+/// Storage key of the car registry.
+pub const CARS_STORAGE_KEY: &str = "cars";
+
+const MAX_CAR_ID_LENGTH: usize = 32;
+/// The registry is stored as one blob, which has to stay small enough for the NVS partition.
+const MAX_CARS: usize = 16;
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "error", rename_all = "kebab-case")]
+pub enum CarRegistryError {
+    InvalidCarId {
+        car_id: String,
+    },
+    UnknownCar {
+        car_id: String,
+    },
+    CarAlreadyRegistered {
+        car_id: String,
+    },
+    /// The car is connected or on a trip, so its profile cannot change.
+    CarInUse {
+        car_id: String,
+    },
+    /// A trip names a car, but another one is connected.
+    OtherCarConnected {
+        car_id: String,
+    },
+    /// Either `charge_limit_wh` or `charge_limit_percent` may be given, and not 0.
+    InvalidChargeLimit,
+    TooManyCars,
+}
+
+impl CommandError for CarRegistryError {
+    fn code(&self) -> u16 {
+        match self {
+            CarRegistryError::InvalidCarId { .. } => 700,
+            CarRegistryError::UnknownCar { .. } => 701,
+            CarRegistryError::CarAlreadyRegistered { .. } => 702,
+            CarRegistryError::CarInUse { .. } => 703,
+            CarRegistryError::OtherCarConnected { .. } => 704,
+            CarRegistryError::InvalidChargeLimit => 705,
+            CarRegistryError::TooManyCars => 706,
+        }
+    }
+}
+
+impl Display for CarRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CarRegistryError::InvalidCarId { car_id } => write!(
+                f,
+                "Car id `{car_id}` must have between 1 and {MAX_CAR_ID_LENGTH} letters, digits, '-' or '_'"
+            ),
+            CarRegistryError::UnknownCar { car_id } => write!(f, "No car registered as `{car_id}`"),
+            CarRegistryError::CarAlreadyRegistered { car_id } => {
+                write!(f, "Car `{car_id}` is already registered")
+            }
+            CarRegistryError::CarInUse { car_id } => {
+                write!(f, "Car `{car_id}` is connected or on a trip")
+            }
+            CarRegistryError::OtherCarConnected { car_id } => {
+                write!(f, "Car `{car_id}` is not the connected car")
+            }
+            CarRegistryError::InvalidChargeLimit => write!(
+                f,
+                "Charge limit must be given in wh or between 1 and 100 percent"
+            ),
+            CarRegistryError::TooManyCars => {
+                write!(f, "At most {MAX_CARS} cars can be registered")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CarRegistryError {}
+
+/// A registered car as listed by the list-cars command.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CarListing {
+    pub car_id: String,
+    #[serde(flatten)]
+    pub car: Car,
+}
+
+/// Cars known to the charging controller, keyed by id. The charging controller holds the
+/// `Arc` of the car it is connected to or driving with, so changes to the car show up here.
+#[derive(Default)]
+pub struct CarRegistry {
+    cars: BTreeMap<String, Arc<RwLock<Car>>>,
+}
+
+impl CarRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(storage: &mut dyn Storage) -> Result<Self> {
+        let cars: BTreeMap<String, Car> = load_json(storage, CARS_STORAGE_KEY)?.unwrap_or_default();
+        Ok(CarRegistry {
+            cars: cars
+                .into_iter()
+                .map(|(car_id, car)| (car_id, Arc::new(RwLock::new(car))))
+                .collect(),
+        })
+    }
+
+    pub fn store(&self, storage: &mut dyn Storage) -> Result<()> {
+        let cars: BTreeMap<&str, Car> = self
+            .cars
+            .iter()
+            .map(|(car_id, car_rwlock)| {
+                (
+                    car_id.as_str(),
+                    *car_rwlock.read().expect("Failed read access on car_rwlock"),
+                )
+            })
+            .collect();
+        store_json(storage, CARS_STORAGE_KEY, &cars)
+    }
+
+    pub fn get(&self, car_id: &str) -> Result<Arc<RwLock<Car>>, CarRegistryError> {
+        self.cars
+            .get(car_id)
+            .cloned()
+            .ok_or_else(|| CarRegistryError::UnknownCar {
+                car_id: car_id.to_string(),
+            })
+    }
+
+    /// Id of the car behind `car_rwlock`, if it is registered.
+    pub fn id_of(&self, car_rwlock: &Arc<RwLock<Car>>) -> Option<&str> {
+        self.cars
+            .iter()
+            .find(|(_, registered)| Arc::ptr_eq(registered, car_rwlock))
+            .map(|(car_id, _)| car_id.as_str())
+    }
+
+    pub fn list(&self) -> Vec<CarListing> {
+        self.cars
+            .iter()
+            .map(|(car_id, car_rwlock)| CarListing {
+                car_id: car_id.clone(),
+                car: *car_rwlock.read().expect("Failed read access on car_rwlock"),
+            })
+            .collect()
+    }
+
+    pub fn register(&mut self, car_id: &str, car: Car) -> Result<(), CarRegistryError> {
+        let is_valid = !car_id.is_empty()
+            && car_id.len() <= MAX_CAR_ID_LENGTH
+            && car_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid {
+            Err(CarRegistryError::InvalidCarId {
+                car_id: car_id.to_string(),
+            })
+        } else if self.cars.contains_key(car_id) {
+            Err(CarRegistryError::CarAlreadyRegistered {
+                car_id: car_id.to_string(),
+            })
+        } else if self.cars.len() >= MAX_CARS {
+            Err(CarRegistryError::TooManyCars)
+        } else {
+            self.cars
+                .insert(car_id.to_string(), Arc::new(RwLock::new(car)));
+            Ok(())
+        }
+    }
+
+    /// Replaces the profile of a car that is neither connected nor on a trip. A profile without
+    /// a charge limit keeps the car's limit, capped at the new capacity.
+    pub fn update(
+        &mut self,
+        car_id: &str,
+        mut car: Car,
+        charging_controller: &ChargingController,
+    ) -> Result<(), CarRegistryError> {
+        let car_rwlock = self.unused(car_id, charging_controller)?;
+        let mut registered = car_rwlock
+            .write()
+            .expect("Failed write access on car_rwlock");
+        if car.charge_limit_wh().is_none() {
+            let charge_limit_wh = registered
+                .charge_limit_wh()
+                .map(|charge_limit_wh| charge_limit_wh.min(car.charging_capacity_wh()));
+            car.set_charge_limit(charge_limit_wh)
+                .expect("Charge limit capped at the capacity");
+        }
+        *registered = car;
+        Ok(())
+    }
+
+    /// Removes a car that is neither connected nor on a trip.
+    pub fn remove(
+        &mut self,
+        car_id: &str,
+        charging_controller: &ChargingController,
+    ) -> Result<(), CarRegistryError> {
+        self.unused(car_id, charging_controller)?;
+        self.cars.remove(car_id);
+        Ok(())
+    }
+
+    fn unused(
+        &self,
+        car_id: &str,
+        charging_controller: &ChargingController,
+    ) -> Result<Arc<RwLock<Car>>, CarRegistryError> {
+        let car_rwlock = self.get(car_id)?;
+        if charging_controller
+            .car_rwlock()
+            .is_some_and(|used| Arc::ptr_eq(used, &car_rwlock))
+        {
+            Err(CarRegistryError::CarInUse {
+                car_id: car_id.to_string(),
+            })
+        } else {
+            Ok(car_rwlock)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn car() -> Car {
+        Car::new(1000, 0, 100).unwrap()
+    }
+
+    #[test]
+    fn registers_valid_ids_only() {
+        let mut car_registry = CarRegistry::new();
+        for car_id in ["a", "Van-1_x", &"x".repeat(MAX_CAR_ID_LENGTH)] {
+            assert_eq!(car_registry.register(car_id, car()), Ok(()), "{car_id}");
+        }
+        for car_id in [
+            "",
+            "my van",
+            "van/1",
+            "vän",
+            &"x".repeat(MAX_CAR_ID_LENGTH + 1),
+        ] {
+            assert_eq!(
+                car_registry.register(car_id, car()),
+                Err(CarRegistryError::InvalidCarId {
+                    car_id: car_id.to_string()
+                })
+            );
+        }
+        assert_eq!(car_registry.list().len(), 3);
+    }
+
+    #[test]
+    fn refuses_duplicate_registration() {
+        let mut car_registry = CarRegistry::new();
+        car_registry.register("van", car()).unwrap();
+        assert_eq!(
+            car_registry.register("van", Car::new(2000, 0, 100).unwrap()),
+            Err(CarRegistryError::CarAlreadyRegistered {
+                car_id: "van".to_string()
+            })
+        );
+        assert_eq!(*car_registry.get("van").unwrap().read().unwrap(), car());
+    }
+
+    #[test]
+    fn refuses_cars_beyond_limit() {
+        let mut car_registry = CarRegistry::new();
+        for index in 0..MAX_CARS {
+            car_registry
+                .register(&format!("car-{index}"), car())
+                .unwrap();
+        }
+        assert_eq!(
+            car_registry.register("one-more", car()),
+            Err(CarRegistryError::TooManyCars)
+        );
+        car_registry
+            .remove("car-0", &ChargingController::new())
+            .unwrap();
+        assert_eq!(car_registry.register("one-more", car()), Ok(()));
+    }
+
+    #[test]
+    fn refuses_to_remove_car_in_use() {
+        let mut car_registry = CarRegistry::new();
+        car_registry.register("van", car()).unwrap();
+        car_registry.register("bus", car()).unwrap();
+        let in_use = CarRegistryError::CarInUse {
+            car_id: "van".to_string(),
+        };
+
+        let mut charging_controller = ChargingController::new();
+        charging_controller
+            .connect_car(car_registry.get("van").unwrap())
+            .unwrap();
+        assert_eq!(
+            car_registry.remove("van", &charging_controller),
+            Err(in_use.clone())
+        );
+        let mut driving_controller = ChargingController::new();
+        driving_controller
+            .start_driving(car_registry.get("van").unwrap())
+            .unwrap();
+        assert_eq!(car_registry.remove("van", &driving_controller), Err(in_use));
+
+        assert_eq!(car_registry.remove("bus", &charging_controller), Ok(()));
+        assert_eq!(
+            car_registry.get("bus").unwrap_err(),
+            CarRegistryError::UnknownCar {
+                car_id: "bus".to_string()
+            }
+        );
+        assert_eq!(
+            car_registry.remove("bus", &charging_controller),
+            Err(CarRegistryError::UnknownCar {
+                car_id: "bus".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn store_and_load_round_trip() {
+        let mut car_registry = CarRegistry::new();
+        let mut van = Car::new(1000, 420, 100).unwrap();
+        van.set_charge_limit(Some(800)).unwrap();
+        car_registry.register("van", van).unwrap();
+        car_registry.register("bus", car()).unwrap();
+        let mut storage = MemoryStorage::new();
+        car_registry.store(&mut storage).unwrap();
+
+        let loaded = CarRegistry::load(&mut storage).unwrap();
+        assert_eq!(loaded.list(), car_registry.list());
+        assert!(CarRegistry::load(&mut MemoryStorage::new())
+            .unwrap()
+            .list()
+            .is_empty());
+    }
+
+    fn registry_with_limited_car() -> CarRegistry {
+        let mut car = Car::new(1000, 0, 100).unwrap();
+        car.set_charge_limit(Some(800)).unwrap();
+        let mut car_registry = CarRegistry::new();
+        car_registry.register("van", car).unwrap();
+        car_registry
+    }
+
+    fn charge_limit_wh(car_registry: &CarRegistry) -> Option<u32> {
+        car_registry
+            .get("van")
+            .unwrap()
+            .read()
+            .unwrap()
+            .charge_limit_wh()
+    }
+
+    #[test]
+    fn update_keeps_charge_limit() {
+        let mut car_registry = registry_with_limited_car();
+        car_registry
+            .update(
+                "van",
+                Car::new(1200, 0, 100).unwrap(),
+                &ChargingController::new(),
+            )
+            .unwrap();
+        assert_eq!(charge_limit_wh(&car_registry), Some(800));
+
+        car_registry
+            .update(
+                "van",
+                Car::new(600, 0, 100).unwrap(),
+                &ChargingController::new(),
+            )
+            .unwrap();
+        assert_eq!(charge_limit_wh(&car_registry), Some(600));
+    }
+
+    #[test]
+    fn update_replaces_charge_limit() {
+        let mut car_registry = registry_with_limited_car();
+        let mut car = Car::new(1000, 0, 100).unwrap();
+        car.set_charge_limit(Some(700)).unwrap();
+        car_registry
+            .update("van", car, &ChargingController::new())
+            .unwrap();
+        assert_eq!(charge_limit_wh(&car_registry), Some(700));
+    }
+}
@@ -1,11 +1,8 @@
-use anyhow::Result;
 use log::info;
-use std::{
-    io::{Error, ErrorKind},
-    sync::{Arc, RwLock},
-};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
 
-use crate::car::Car;
+use crate::{car::Car, charging_error::ChargingError};
 
 pub enum ChargingController {
     Disconnected,
@@ -16,6 +13,27 @@ pub enum ChargingController {
         car_rwlock: Arc<RwLock<Car>>,
         charging_speed_w: u32,
     },
+    /// The car is out on a trip. `reconnect` tells whether it was connected before and is
+    /// connected again once the trip is over.
+    Driving {
+        car_rwlock: Arc<RwLock<Car>>,
+        reconnect: bool,
+    },
+}
+
+/// State of the [`ChargingController`] without the car, for status output.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ChargingControllerState {
+    Disconnected,
+    Connected,
+    Charging,
+    Driving,
+}
+
+impl Default for ChargingController {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ChargingController {
@@ -23,52 +41,74 @@ impl ChargingController {
         ChargingController::Disconnected
     }
 
-    pub fn connect_car(&mut self, car_rwlock: Arc<RwLock<Car>>) -> Result<()> {
+    pub fn state(&self) -> ChargingControllerState {
+        match self {
+            ChargingController::Disconnected => ChargingControllerState::Disconnected,
+            ChargingController::Connected { .. } => ChargingControllerState::Connected,
+            ChargingController::Charging { .. } => ChargingControllerState::Charging,
+            ChargingController::Driving { .. } => ChargingControllerState::Driving,
+        }
+    }
+
+    /// Car the controller is connected to or driving with.
+    pub fn car_rwlock(&self) -> Option<&Arc<RwLock<Car>>> {
+        match self {
+            ChargingController::Disconnected => None,
+            ChargingController::Connected { car_rwlock }
+            | ChargingController::Charging { car_rwlock, .. }
+            | ChargingController::Driving { car_rwlock, .. } => Some(car_rwlock),
+        }
+    }
+
+    /// Charging speed the car is currently charged with, if charging.
+    pub fn charging_speed_w(&self) -> Option<u32> {
+        match self {
+            ChargingController::Charging {
+                charging_speed_w, ..
+            } => Some(*charging_speed_w),
+            _ => None,
+        }
+    }
+
+    pub fn connect_car(&mut self, car_rwlock: Arc<RwLock<Car>>) -> Result<(), ChargingError> {
         match self {
             ChargingController::Disconnected => {
                 *self = ChargingController::Connected { car_rwlock };
                 info!("Car connected");
             }
-            _ => Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Car is already connected",
-            ))?,
+            ChargingController::Driving { .. } => Err(ChargingError::CarDriving)?,
+            _ => Err(ChargingError::CarAlreadyConnected)?,
         }
         Ok(())
     }
 
-    pub fn disconnect_car(&mut self) -> Result<()> {
+    pub fn disconnect_car(&mut self) -> Result<(), ChargingError> {
         match self {
             ChargingController::Connected { car_rwlock: _ } => {
                 *self = ChargingController::Disconnected;
                 info!("Car disconnected");
             }
-            ChargingController::Charging { .. } => Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Cannot disconnect while charging",
-            ))?,
-            ChargingController::Disconnected => {
-                Err(Error::new(ErrorKind::InvalidInput, "No car connected"))?
-            }
+            ChargingController::Charging { .. } => Err(ChargingError::DisconnectWhileCharging)?,
+            ChargingController::Driving { .. } => Err(ChargingError::CarDriving)?,
+            ChargingController::Disconnected => Err(ChargingError::NoCarConnected)?,
         };
         Ok(())
     }
 
-    pub fn start_charging(&mut self, charging_speed_w: u32) -> Result<()> {
+    pub fn start_charging(&mut self, charging_speed_w: u32) -> Result<(), ChargingError> {
         match self {
             ChargingController::Connected { car_rwlock } => {
                 {
                     let car = car_rwlock.read().expect("Failed read access on car_rwlock");
                     if charging_speed_w > car.max_charging_speed_w {
-                        Err(Error::new(
-                            ErrorKind::InvalidInput,
-                            "Charging speed exceeds car's maximum charging speed",
-                        ))?
+                        Err(ChargingError::ExceedsMaxChargingSpeed {
+                            requested_w: charging_speed_w,
+                            max_w: car.max_charging_speed_w,
+                        })?
                     } else if car.is_fully_charged() {
-                        Err(Error::new(
-                            ErrorKind::InvalidInput,
-                            "Car is already fully charged",
-                        ))?
+                        Err(ChargingError::AlreadyFullyCharged {
+                            current_charge_wh: car.current_charge_wh(),
+                        })?
                     }
                 }
                 *self = ChargingController::Charging {
@@ -77,32 +117,31 @@ impl ChargingController {
                 };
                 info!("Charging started with charging speed: {charging_speed_w}w");
             }
-            ChargingController::Charging { .. } => {
-                Err(Error::new(ErrorKind::InvalidInput, "Already charging"))?
-            }
-            ChargingController::Disconnected => {
-                Err(Error::new(ErrorKind::InvalidInput, "No car connected"))?
-            }
+            ChargingController::Charging { .. } => Err(ChargingError::AlreadyCharging)?,
+            ChargingController::Driving { .. } => Err(ChargingError::ChargeWhileDriving)?,
+            ChargingController::Disconnected => Err(ChargingError::NoCarConnected)?,
         }
         Ok(())
     }
 
-    pub fn change_charging_speed(&mut self, new_charging_speed_w: u32) -> Result<()> {
+    pub fn change_charging_speed(
+        &mut self,
+        new_charging_speed_w: u32,
+    ) -> Result<(), ChargingError> {
         match self {
             ChargingController::Charging {
                 car_rwlock,
                 charging_speed_w: _,
             } => {
-                if new_charging_speed_w
-                    > car_rwlock
-                        .read()
-                        .expect("Failed read access on car_rwlock")
-                        .max_charging_speed_w
-                {
-                    Err(Error::new(
-                        ErrorKind::InvalidInput,
-                        "Charging speed exceeds car's maximum charging speed",
-                    ))?
+                let max_charging_speed_w = car_rwlock
+                    .read()
+                    .expect("Failed read access on car_rwlock")
+                    .max_charging_speed_w;
+                if new_charging_speed_w > max_charging_speed_w {
+                    Err(ChargingError::ExceedsMaxChargingSpeed {
+                        requested_w: new_charging_speed_w,
+                        max_w: max_charging_speed_w,
+                    })?
                 } else {
                     *self = ChargingController::Charging {
                         car_rwlock: car_rwlock.clone(),
@@ -112,14 +151,11 @@ impl ChargingController {
                 }
                 Ok(())
             }
-            _ => Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Not currently charging",
-            ))?,
+            _ => Err(ChargingError::NotCharging)?,
         }
     }
 
-    pub fn stop_charging(&mut self) -> Result<()> {
+    pub fn stop_charging(&mut self) -> Result<(), ChargingError> {
         match self {
             ChargingController::Charging { car_rwlock, .. } => {
                 *self = ChargingController::Connected {
@@ -127,11 +163,214 @@ impl ChargingController {
                 };
                 info!("Charging stopped")
             }
-            _ => Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Not currently charging",
-            ))?,
+            _ => Err(ChargingError::NotCharging)?,
+        }
+        Ok(())
+    }
+
+    /// Sends the car on a trip. A connected car is reconnected afterwards, a disconnected one
+    /// is given as `car_rwlock`.
+    pub fn start_driving(&mut self, car_rwlock: Arc<RwLock<Car>>) -> Result<(), ChargingError> {
+        match self {
+            ChargingController::Disconnected => {
+                *self = ChargingController::Driving {
+                    car_rwlock,
+                    reconnect: false,
+                };
+            }
+            ChargingController::Connected { car_rwlock } => {
+                *self = ChargingController::Driving {
+                    car_rwlock: car_rwlock.clone(),
+                    reconnect: true,
+                };
+            }
+            ChargingController::Charging { .. } => Err(ChargingError::TripWhileCharging)?,
+            ChargingController::Driving { .. } => Err(ChargingError::AlreadyDriving)?,
+        }
+        info!("Driving started");
+        Ok(())
+    }
+
+    pub fn stop_driving(&mut self) -> Result<(), ChargingError> {
+        match self {
+            ChargingController::Driving {
+                car_rwlock,
+                reconnect: true,
+            } => {
+                *self = ChargingController::Connected {
+                    car_rwlock: car_rwlock.clone(),
+                };
+            }
+            ChargingController::Driving {
+                reconnect: false, ..
+            } => {
+                *self = ChargingController::Disconnected;
+            }
+            _ => Err(ChargingError::NotDriving)?,
         }
+        info!("Driving stopped");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn car_rwlock(current_charge_wh: u32) -> Arc<RwLock<Car>> {
+        Arc::new(RwLock::new(Car::new(1000, current_charge_wh, 100).unwrap()))
+    }
+
+    fn connected(current_charge_wh: u32) -> ChargingController {
+        let mut charging_controller = ChargingController::new();
+        charging_controller
+            .connect_car(car_rwlock(current_charge_wh))
+            .unwrap();
+        charging_controller
+    }
+
+    #[test]
+    fn connects_and_disconnects() {
+        let mut charging_controller = ChargingController::new();
+        assert_eq!(
+            charging_controller.state(),
+            ChargingControllerState::Disconnected
+        );
+        assert_eq!(
+            charging_controller.disconnect_car(),
+            Err(ChargingError::NoCarConnected)
+        );
+        charging_controller.connect_car(car_rwlock(0)).unwrap();
+        assert_eq!(
+            charging_controller.state(),
+            ChargingControllerState::Connected
+        );
+        assert_eq!(
+            charging_controller.connect_car(car_rwlock(0)),
+            Err(ChargingError::CarAlreadyConnected)
+        );
+        charging_controller.disconnect_car().unwrap();
+        assert!(charging_controller.car_rwlock().is_none());
+    }
+
+    #[test]
+    fn charges_a_connected_car() {
+        let mut charging_controller = ChargingController::new();
+        assert_eq!(
+            charging_controller.start_charging(10),
+            Err(ChargingError::NoCarConnected)
+        );
+        let mut charging_controller = connected(0);
+        assert_eq!(
+            charging_controller.start_charging(101),
+            Err(ChargingError::ExceedsMaxChargingSpeed {
+                requested_w: 101,
+                max_w: 100,
+            })
+        );
+        charging_controller.start_charging(50).unwrap();
+        assert_eq!(
+            charging_controller.state(),
+            ChargingControllerState::Charging
+        );
+        assert_eq!(charging_controller.charging_speed_w(), Some(50));
+        assert_eq!(
+            charging_controller.start_charging(50),
+            Err(ChargingError::AlreadyCharging)
+        );
+        assert_eq!(
+            charging_controller.disconnect_car(),
+            Err(ChargingError::DisconnectWhileCharging)
+        );
+
+        charging_controller.change_charging_speed(80).unwrap();
+        assert_eq!(charging_controller.charging_speed_w(), Some(80));
+        assert!(charging_controller.change_charging_speed(101).is_err());
+        assert_eq!(charging_controller.charging_speed_w(), Some(80));
+
+        charging_controller.stop_charging().unwrap();
+        assert_eq!(
+            charging_controller.state(),
+            ChargingControllerState::Connected
+        );
+        assert_eq!(
+            charging_controller.stop_charging(),
+            Err(ChargingError::NotCharging)
+        );
+        assert_eq!(
+            charging_controller.change_charging_speed(10),
+            Err(ChargingError::NotCharging)
+        );
+    }
+
+    #[test]
+    fn refuses_to_charge_a_full_car() {
+        let mut charging_controller = connected(990);
+        assert_eq!(
+            charging_controller.start_charging(10),
+            Err(ChargingError::AlreadyFullyCharged {
+                current_charge_wh: 990
+            })
+        );
+        assert_eq!(
+            charging_controller.state(),
+            ChargingControllerState::Connected
+        );
+    }
+
+    #[test]
+    fn reconnects_a_connected_car_after_a_trip() {
+        let mut charging_controller = connected(500);
+        let car_rwlock = charging_controller.car_rwlock().unwrap().clone();
+        charging_controller.start_charging(10).unwrap();
+        assert_eq!(
+            charging_controller.start_driving(car_rwlock.clone()),
+            Err(ChargingError::TripWhileCharging)
+        );
+        charging_controller.stop_charging().unwrap();
+
+        charging_controller
+            .start_driving(car_rwlock.clone())
+            .unwrap();
+        assert_eq!(
+            charging_controller.state(),
+            ChargingControllerState::Driving
+        );
+        assert_eq!(
+            charging_controller.start_charging(10),
+            Err(ChargingError::ChargeWhileDriving)
+        );
+        assert_eq!(
+            charging_controller.start_driving(car_rwlock.clone()),
+            Err(ChargingError::AlreadyDriving)
+        );
+        charging_controller.stop_driving().unwrap();
+        assert_eq!(
+            charging_controller.state(),
+            ChargingControllerState::Connected
+        );
+        assert!(Arc::ptr_eq(
+            charging_controller.car_rwlock().unwrap(),
+            &car_rwlock
+        ));
+    }
+
+    #[test]
+    fn leaves_a_disconnected_car_disconnected_after_a_trip() {
+        let mut charging_controller = ChargingController::new();
+        assert_eq!(
+            charging_controller.stop_driving(),
+            Err(ChargingError::NotDriving)
+        );
+        charging_controller.start_driving(car_rwlock(500)).unwrap();
+        assert_eq!(
+            charging_controller.connect_car(car_rwlock(0)),
+            Err(ChargingError::CarDriving)
+        );
+        charging_controller.stop_driving().unwrap();
+        assert_eq!(
+            charging_controller.state(),
+            ChargingControllerState::Disconnected
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt::{self, Display};
+
+use serde::Serialize;
+
+use crate::command_response::CommandError;
+
+/// Rejections of the charging controller and the car. Each variant has a stable numeric
+/// [`code`](CommandError::code) and a stable string name (the serialized `error` tag), so
+/// the backend and tests can tell failures apart without parsing messages.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "error", rename_all = "kebab-case")]
+pub enum ChargingError {
+    ChargeExceedsCapacity {
+        charge_wh: u32,
+        charging_capacity_wh: u32,
+    },
+    NoCarConnected,
+    CarAlreadyConnected,
+    CarDriving,
+    DisconnectWhileCharging,
+    ExceedsMaxChargingSpeed {
+        requested_w: u32,
+        max_w: u32,
+    },
+    AlreadyFullyCharged {
+        current_charge_wh: u32,
+    },
+    AlreadyCharging,
+    NotCharging,
+    ChargeWhileDriving,
+    ChargeWhileCalibrating,
+    TripWhileCharging,
+    AlreadyDriving,
+    NotDriving,
+    NotEnoughChargeForTrip {
+        energy_usage_w: u32,
+        current_charge_wh: u32,
+    },
+    CalibrateWhileCharging,
+}
+
+impl CommandError for ChargingError {
+    fn code(&self) -> u16 {
+        match self {
+            ChargingError::ChargeExceedsCapacity { .. } => 100,
+            ChargingError::NoCarConnected => 200,
+            ChargingError::CarAlreadyConnected => 201,
+            ChargingError::CarDriving => 202,
+            ChargingError::DisconnectWhileCharging => 203,
+            ChargingError::ExceedsMaxChargingSpeed { .. } => 300,
+            ChargingError::AlreadyFullyCharged { .. } => 301,
+            ChargingError::AlreadyCharging => 302,
+            ChargingError::NotCharging => 303,
+            ChargingError::ChargeWhileDriving => 304,
+            ChargingError::ChargeWhileCalibrating => 305,
+            ChargingError::TripWhileCharging => 400,
+            ChargingError::AlreadyDriving => 401,
+            ChargingError::NotDriving => 402,
+            ChargingError::NotEnoughChargeForTrip { .. } => 403,
+            ChargingError::CalibrateWhileCharging => 500,
+        }
+    }
+}
+
+impl Display for ChargingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChargingError::ChargeExceedsCapacity {
+                charge_wh,
+                charging_capacity_wh,
+            } => write!(
+                f,
+                "Charge of {charge_wh}wh exceeds charging capacity of {charging_capacity_wh}wh"
+            ),
+            ChargingError::NoCarConnected => write!(f, "No car connected"),
+            ChargingError::CarAlreadyConnected => write!(f, "Car is already connected"),
+            ChargingError::CarDriving => write!(f, "Car is driving"),
+            ChargingError::DisconnectWhileCharging => {
+                write!(f, "Cannot disconnect while charging")
+            }
+            ChargingError::ExceedsMaxChargingSpeed { requested_w, max_w } => write!(
+                f,
+                "Charging speed of {requested_w}w exceeds car's maximum charging speed of {max_w}w"
+            ),
+            ChargingError::AlreadyFullyCharged { current_charge_wh } => write!(
+                f,
+                "Car is already fully charged with {current_charge_wh}wh"
+            ),
+            ChargingError::AlreadyCharging => write!(f, "Already charging"),
+            ChargingError::NotCharging => write!(f, "Not currently charging"),
+            ChargingError::ChargeWhileDriving => write!(f, "Cannot charge while driving"),
+            ChargingError::ChargeWhileCalibrating => write!(f, "Cannot charge while calibrating"),
+            ChargingError::TripWhileCharging => write!(f, "Cannot start trip while charging"),
+            ChargingError::AlreadyDriving => write!(f, "Already driving"),
+            ChargingError::NotDriving => write!(f, "Not currently driving"),
+            ChargingError::NotEnoughChargeForTrip {
+                energy_usage_w,
+                current_charge_wh,
+            } => write!(
+                f,
+                "Not enough charge for trip, needs {energy_usage_w}wh but car has {current_charge_wh}wh"
+            ),
+            ChargingError::CalibrateWhileCharging => write!(f, "Cannot calibrate while charging"),
+        }
+    }
+}
+
+impl std::error::Error for ChargingError {}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn codes_and_names_are_stable() {
+        let errors = [
+            (
+                ChargingError::ChargeExceedsCapacity {
+                    charge_wh: 1200,
+                    charging_capacity_wh: 1000,
+                },
+                100,
+                json!({"error":"charge-exceeds-capacity","charge_wh":1200,"charging_capacity_wh":1000}),
+            ),
+            (
+                ChargingError::NoCarConnected,
+                200,
+                json!({"error":"no-car-connected"}),
+            ),
+            (
+                ChargingError::CarAlreadyConnected,
+                201,
+                json!({"error":"car-already-connected"}),
+            ),
+            (
+                ChargingError::CarDriving,
+                202,
+                json!({"error":"car-driving"}),
+            ),
+            (
+                ChargingError::DisconnectWhileCharging,
+                203,
+                json!({"error":"disconnect-while-charging"}),
+            ),
+            (
+                ChargingError::ExceedsMaxChargingSpeed {
+                    requested_w: 200,
+                    max_w: 100,
+                },
+                300,
+                json!({"error":"exceeds-max-charging-speed","requested_w":200,"max_w":100}),
+            ),
+            (
+                ChargingError::AlreadyFullyCharged {
+                    current_charge_wh: 990,
+                },
+                301,
+                json!({"error":"already-fully-charged","current_charge_wh":990}),
+            ),
+            (
+                ChargingError::AlreadyCharging,
+                302,
+                json!({"error":"already-charging"}),
+            ),
+            (
+                ChargingError::NotCharging,
+                303,
+                json!({"error":"not-charging"}),
+            ),
+            (
+                ChargingError::ChargeWhileDriving,
+                304,
+                json!({"error":"charge-while-driving"}),
+            ),
+            (
+                ChargingError::ChargeWhileCalibrating,
+                305,
+                json!({"error":"charge-while-calibrating"}),
+            ),
+            (
+                ChargingError::TripWhileCharging,
+                400,
+                json!({"error":"trip-while-charging"}),
+            ),
+            (
+                ChargingError::AlreadyDriving,
+                401,
+                json!({"error":"already-driving"}),
+            ),
+            (
+                ChargingError::NotDriving,
+                402,
+                json!({"error":"not-driving"}),
+            ),
+            (
+                ChargingError::NotEnoughChargeForTrip {
+                    energy_usage_w: 500,
+                    current_charge_wh: 300,
+                },
+                403,
+                json!({"error":"not-enough-charge-for-trip","energy_usage_w":500,"current_charge_wh":300}),
+            ),
+            (
+                ChargingError::CalibrateWhileCharging,
+                500,
+                json!({"error":"calibrate-while-charging"}),
+            ),
+        ];
+        for (error, code, serialized) in errors {
+            assert_eq!(error.code(), code, "{error:?}");
+            assert_eq!(serde_json::to_value(error).unwrap(), serialized);
+        }
+    }
+}
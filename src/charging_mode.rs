@@ -0,0 +1,108 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    charging_schedule::apply_charging_schedule,
+    context::Context,
+    controller_status::queue_status,
+    departure_charging::apply_departure_charging,
+    mqtt::MqttPublisher,
+    solar_charging::{apply_solar_charging, SolarMode},
+};
+
+/// What decides the charging speed. The automatic modes take precedence in the order departure,
+/// solar, schedule; the manual commands override them.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChargingMode {
+    #[default]
+    Manual,
+    Schedule,
+    Departure,
+    Solar,
+}
+
+/// The mode in charge from the departure plan, the solar settings and the schedule. Must not be
+/// called with any of their locks held.
+fn active_charging_mode(context: &Context) -> ChargingMode {
+    if context
+        .departure_plan_mutex
+        .lock()
+        .expect("Failed lock on departure_plan_mutex")
+        .is_some()
+    {
+        return ChargingMode::Departure;
+    }
+    let solar_mode = context
+        .solar_settings_rwlock
+        .read()
+        .expect("Failed read access on solar_settings_rwlock")
+        .mode;
+    let solar_overridden = context
+        .solar_state_mutex
+        .lock()
+        .expect("Failed lock on solar_state_mutex")
+        .is_overridden();
+    if solar_mode != SolarMode::Off && !solar_overridden {
+        return ChargingMode::Solar;
+    }
+    if context
+        .schedule_state_mutex
+        .lock()
+        .expect("Failed lock on schedule_state_mutex")
+        .is_active()
+    {
+        ChargingMode::Schedule
+    } else {
+        ChargingMode::Manual
+    }
+}
+
+/// Stores the mode in charge for the status. Returns whether it changed.
+pub fn refresh_charging_mode(context: &Context) -> bool {
+    let mode = active_charging_mode(context);
+    let mut charging_mode = context
+        .charging_mode_mutex
+        .lock()
+        .expect("Failed lock on charging_mode_mutex");
+    let changed = *charging_mode != mode;
+    *charging_mode = mode;
+    changed
+}
+
+/// Runs the automatic charging modes for one tick, the ones needing the wall clock only once it
+/// is set, none while a calibration sweep is pending. A failing mode does not keep the others from running, the first error is returned.
+/// Queues the status when the mode in charge changes.
+pub async fn apply_charging_modes(
+    context: &Context,
+    unix_time_s: Option<u64>,
+    solar_power_w: f32,
+    elapsed: Duration,
+    mqtt_client: &mut impl MqttPublisher,
+) -> Result<()> {
+    if context.calibration_requested.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let departure_result = match unix_time_s {
+        Some(unix_time_s) => apply_departure_charging(context, unix_time_s, mqtt_client).await,
+        None => Ok(()),
+    };
+    let solar_result = apply_solar_charging(context, solar_power_w, elapsed);
+    let schedule_result = match unix_time_s {
+        Some(unix_time_s) => apply_charging_schedule(context, unix_time_s),
+        None => Ok(()),
+    };
+
+    {
+        let charging_controller = context
+            .charging_controller_mutex
+            .lock()
+            .expect("Failed lock on charging_controller_mutex");
+        if refresh_charging_mode(context) {
+            queue_status(context, &charging_controller);
+        }
+    }
+    departure_result.and(solar_result).and(schedule_result)
+}
@@ -0,0 +1,229 @@
+use std::fmt::Debug;
+
+use anyhow::Result;
+use embedded_hal::{blocking::i2c, digital::v2::OutputPin};
+use ina219::INA219;
+use log::debug;
+use serde::Serialize;
+
+use crate::{
+    calibration::ChargingCalibration,
+    context::Context,
+    hardware_controller::HardwareController,
+    i2c::build_ina_stats,
+    mqtt::{MqttPublisher, QoS},
+    tpl_potentiometer::MAX_WIPER_STEP,
+};
+
+pub const REGULATOR_TOPIC: &str = "regulator";
+
+/// Wiper steps per watt of control error.
+const PROPORTIONAL_GAIN: f32 = 0.5;
+/// Wiper steps per watt of accumulated control error, integrated once per tick.
+const INTEGRAL_GAIN: f32 = 0.2;
+/// Bound of the accumulated control error, enough for the integral term to span the wiper range.
+const MAX_INTEGRAL_W: f32 = MAX_WIPER_STEP as f32 / INTEGRAL_GAIN;
+/// Largest wiper movement in one tick, so a noisy measurement cannot slam the output.
+const MAX_WIPER_STEP_CHANGE: u8 = 4;
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RegulatorStats {
+    pub setpoint_w: f32,
+    pub measured_w: f32,
+    pub error_w: f32,
+    pub integral_w: f32,
+    pub wiper_step: u8,
+}
+
+/// PI controller moving the TPL wiper until the power measured at the power INA219 tracks the
+/// charging speed. The calibration provides the feedforward step, the PI terms correct what it
+/// gets wrong. Integration stops while the output is saturated or rate limited (anti-windup).
+#[derive(Default)]
+pub struct ChargingRegulator {
+    integral_w: f32,
+    wiper_step: Option<u8>,
+    idle_applied: bool,
+}
+
+impl ChargingRegulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets the controller state, e.g. after something else moved the wiper.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Computes the next wiper step for one tick.
+    pub fn update(
+        &mut self,
+        calibration: &ChargingCalibration,
+        setpoint_w: f32,
+        measured_w: f32,
+    ) -> RegulatorStats {
+        let error_w = setpoint_w - measured_w;
+        let feedforward = calibration.wiper_step_for_power(setpoint_w) as f32;
+        let integral_w = (self.integral_w + error_w).clamp(-MAX_INTEGRAL_W, MAX_INTEGRAL_W);
+        let output = feedforward + PROPORTIONAL_GAIN * error_w + INTEGRAL_GAIN * integral_w;
+
+        let mut wiper_step = output.round().clamp(0.0, MAX_WIPER_STEP as f32) as u8;
+        if let Some(previous) = self.wiper_step {
+            wiper_step = wiper_step.clamp(
+                previous.saturating_sub(MAX_WIPER_STEP_CHANGE),
+                previous
+                    .saturating_add(MAX_WIPER_STEP_CHANGE)
+                    .min(MAX_WIPER_STEP),
+            );
+        }
+        if wiper_step as f32 == output.round() {
+            self.integral_w = integral_w;
+        }
+        self.wiper_step = Some(wiper_step);
+        self.idle_applied = false;
+
+        RegulatorStats {
+            setpoint_w,
+            measured_w,
+            error_w,
+            integral_w: self.integral_w,
+            wiper_step,
+        }
+    }
+}
+
+/// One control tick: regulates towards the charging speed while charging, otherwise turns the
+/// charging output down to its lowest current once, the output cannot be switched off.
+/// Publishes and returns the controller's view while charging.
+pub async fn regulate_charging<I2C, E, P>(
+    regulator: &mut ChargingRegulator,
+    context: &Context,
+    hardware_controller: &HardwareController<I2C, P>,
+    power_ina_219: &mut INA219<I2C>,
+    mqtt_client: &mut impl MqttPublisher,
+) -> Result<Option<RegulatorStats>>
+where
+    I2C: i2c::Write<Error = E> + i2c::Read<Error = E>,
+    E: Debug,
+    P: OutputPin,
+    P::Error: Debug,
+{
+    let charging_speed_w = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex")
+        .charging_speed_w();
+    let Some(charging_speed_w) = charging_speed_w else {
+        if !regulator.idle_applied {
+            regulator.reset();
+            hardware_controller.set_lowest_charging_current()?;
+            regulator.idle_applied = true;
+        }
+        return Ok(None);
+    };
+
+    let measured_w = build_ina_stats(power_ina_219)?.power_w();
+    let regulator_stats = {
+        let calibration = context
+            .calibration_rwlock
+            .read()
+            .expect("Failed read access on calibration_rwlock");
+        regulator.update(&calibration, charging_speed_w as f32, measured_w)
+    };
+    hardware_controller.set_wiper_step(regulator_stats.wiper_step)?;
+    debug!("{:?}", regulator_stats);
+
+    let regulator_stats_json = serde_json::to_string(&regulator_stats)?;
+    mqtt_client
+        .publish(
+            REGULATOR_TOPIC,
+            QoS::AtMostOnce,
+            false,
+            regulator_stats_json.as_bytes(),
+        )
+        .await?;
+    Ok(Some(regulator_stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Charging output delivering `efficiency` of the power the calibration expects.
+    fn plant(calibration: &ChargingCalibration, efficiency: f32, wiper_step: u8) -> f32 {
+        efficiency * calibration.power_for_wiper_step(wiper_step)
+    }
+
+    /// Runs `ticks` ticks against the plant and returns the stats of each.
+    fn regulate(
+        regulator: &mut ChargingRegulator,
+        efficiency: f32,
+        setpoint_w: f32,
+        ticks: usize,
+    ) -> Vec<RegulatorStats> {
+        let calibration = ChargingCalibration::default();
+        let mut wiper_step = 0;
+        (0..ticks)
+            .map(|_| {
+                let measured_w = plant(&calibration, efficiency, wiper_step);
+                let regulator_stats = regulator.update(&calibration, setpoint_w, measured_w);
+                wiper_step = regulator_stats.wiper_step;
+                regulator_stats
+            })
+            .collect()
+    }
+
+    #[test]
+    fn settles_at_setpoint() {
+        let calibration = ChargingCalibration::default();
+        // Setpoints within the 2 A the power INA219 measures at 4.5 V.
+        for (efficiency, setpoint_w) in [(1.0, 5.0), (0.8, 5.0), (1.2, 5.0), (0.8, 6.0), (1.0, 1.0)]
+        {
+            let mut regulator = ChargingRegulator::new();
+            let stats = regulate(&mut regulator, efficiency, setpoint_w, 200);
+            let wiper_step = stats[stats.len() - 1].wiper_step;
+            // The wiper moves in steps, so the output settles within one step of the setpoint.
+            let below = plant(&calibration, efficiency, wiper_step.saturating_sub(1));
+            let above = plant(&calibration, efficiency, wiper_step + 1);
+            assert!(
+                below <= setpoint_w && setpoint_w <= above,
+                "{setpoint_w}w at efficiency {efficiency} settled at wiper step {wiper_step}"
+            );
+            assert!(stats[stats.len() - 20..]
+                .iter()
+                .all(|tick| tick.wiper_step.abs_diff(wiper_step) <= 1));
+        }
+    }
+
+    #[test]
+    fn integral_freezes_while_saturated() {
+        let mut regulator = ChargingRegulator::new();
+        // Half the expected power cannot reach a setpoint close to the maximum.
+        let stats = regulate(&mut regulator, 0.5, 40.0, 200);
+        let saturated: Vec<&RegulatorStats> = stats
+            .iter()
+            .filter(|tick| tick.wiper_step == MAX_WIPER_STEP)
+            .collect();
+        assert!(saturated.len() > 100);
+        assert!(saturated
+            .windows(2)
+            .all(|pair| pair[0].integral_w == pair[1].integral_w));
+        assert!(saturated[0].integral_w < MAX_INTEGRAL_W);
+    }
+
+    #[test]
+    fn limits_wiper_change_per_tick() {
+        for (efficiency, setpoint_w) in [(1.0, 40.0), (0.5, 40.0), (2.0, 1.0)] {
+            let mut regulator = ChargingRegulator::new();
+            let stats = regulate(&mut regulator, efficiency, setpoint_w, 100);
+            assert!(stats.windows(2).all(
+                |pair| pair[0].wiper_step.abs_diff(pair[1].wiper_step) <= MAX_WIPER_STEP_CHANGE
+            ));
+        }
+        // A setpoint dropping to the lowest current still moves the wiper by the limit only.
+        let mut regulator = ChargingRegulator::new();
+        let before = regulate(&mut regulator, 1.0, 40.0, 100)[99].wiper_step;
+        let after = regulate(&mut regulator, 1.0, 0.0, 1)[0].wiper_step;
+        assert_eq!(after, before - MAX_WIPER_STEP_CHANGE);
+    }
+}
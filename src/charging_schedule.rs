@@ -0,0 +1,667 @@
+use std::fmt::{self, Display};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    charging_controller::ChargingControllerState,
+    charging_session::{begin_session, end_session, SessionEndReason},
+    command_response::CommandError,
+    context::Context,
+    controller_status::queue_status,
+    mqtt::{Outbox, QoS},
+    solar_charging::SolarMode,
+    storage::{load_json, store_json, Storage},
+};
+
+/// Storage key of the charging schedule.
+pub const CHARGING_SCHEDULE_STORAGE_KEY: &str = "schedule";
+
+pub const CHARGING_SCHEDULE_TOPIC: &str = "charging-schedule";
+
+const MAX_WINDOWS: usize = 16;
+/// Offsets beyond UTC-12:00 and UTC+14:00 do not exist.
+const MIN_UTC_OFFSET_MINUTES: i32 = -12 * 60;
+const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+/// 1970-01-01, the first day of Unix time, was a Thursday.
+const EPOCH_WEEKDAY: u32 = 3;
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "error", rename_all = "kebab-case")]
+pub enum ScheduleError {
+    TooManyWindows { windows: usize },
+    WindowWithoutDays { window: usize },
+    EmptyWindow { window: usize },
+    InvalidChargingSpeed { window: usize },
+    InvalidUtcOffset { utc_offset_minutes: i32 },
+    InvalidTime { time: String },
+}
+
+impl CommandError for ScheduleError {
+    fn code(&self) -> u16 {
+        match self {
+            ScheduleError::TooManyWindows { .. } => 800,
+            ScheduleError::WindowWithoutDays { .. } => 801,
+            ScheduleError::EmptyWindow { .. } => 802,
+            ScheduleError::InvalidChargingSpeed { .. } => 803,
+            ScheduleError::InvalidUtcOffset { .. } => 804,
+            ScheduleError::InvalidTime { .. } => 805,
+        }
+    }
+}
+
+impl Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::TooManyWindows { windows } => write!(
+                f,
+                "Schedule has {windows} windows, at most {MAX_WINDOWS} are supported"
+            ),
+            ScheduleError::WindowWithoutDays { window } => {
+                write!(f, "Window {window} has no days")
+            }
+            ScheduleError::EmptyWindow { window } => {
+                write!(f, "Window {window} starts when it ends")
+            }
+            ScheduleError::InvalidChargingSpeed { window } => {
+                write!(f, "Window {window} has no charging speed")
+            }
+            ScheduleError::InvalidUtcOffset { utc_offset_minutes } => write!(
+                f,
+                "UTC offset of {utc_offset_minutes} minutes is not between {MIN_UTC_OFFSET_MINUTES} and {MAX_UTC_OFFSET_MINUTES}"
+            ),
+            ScheduleError::InvalidTime { time } => {
+                write!(f, "Time `{time}` is not a time of day like 22:00")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    fn index(self) -> u32 {
+        self as u32
+    }
+}
+
+/// Local time of day, written as `HH:MM`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    minutes: u32,
+}
+
+impl TimeOfDay {
+    pub fn new(hour: u32, minute: u32) -> Option<Self> {
+        (hour < 24 && minute < 60).then_some(TimeOfDay {
+            minutes: hour * 60 + minute,
+        })
+    }
+
+    pub fn minutes(&self) -> u32 {
+        self.minutes
+    }
+
+    /// Unix time of the next time the local clock, `utc_offset_minutes` ahead of UTC, shows this
+    /// time of day after `unix_time_s`.
+    pub fn next_after(&self, unix_time_s: u64, utc_offset_minutes: i32) -> u64 {
+        let seconds_per_day = MINUTES_PER_DAY as i64 * 60;
+        let local_time_s = unix_time_s as i64 + utc_offset_minutes as i64 * 60;
+        let seconds_until = (self.minutes as i64 * 60 - local_time_s).rem_euclid(seconds_per_day);
+        let seconds_until = if seconds_until == 0 {
+            seconds_per_day
+        } else {
+            seconds_until
+        };
+        unix_time_s + seconds_until as u64
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = ScheduleError;
+
+    fn try_from(time: String) -> Result<Self, ScheduleError> {
+        // Only digits, `u32::parse` would also take a sign.
+        let is_number = |digits: &str| digits.bytes().all(|digit| digit.is_ascii_digit());
+        time.split_once(':')
+            .filter(|(hour, minute)| {
+                (1..=2).contains(&hour.len())
+                    && minute.len() == 2
+                    && is_number(hour)
+                    && is_number(minute)
+            })
+            .and_then(|(hour, minute)| TimeOfDay::new(hour.parse().ok()?, minute.parse().ok()?))
+            .ok_or(ScheduleError::InvalidTime { time })
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", time.minutes / 60, time.minutes % 60)
+    }
+}
+
+/// Charges at `charging_speed_w` from `start` to `end` on each of `days`. A window that ends at
+/// or before its start runs past midnight into the next day.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChargingWindow {
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    pub charging_speed_w: u32,
+}
+
+impl ChargingWindow {
+    fn contains(&self, minute_of_week: u32) -> bool {
+        let length = (self.end.minutes + MINUTES_PER_DAY - self.start.minutes) % MINUTES_PER_DAY;
+        self.days.iter().any(|day| {
+            let start = day.index() * MINUTES_PER_DAY + self.start.minutes;
+            (minute_of_week + MINUTES_PER_WEEK - start) % MINUTES_PER_WEEK < length
+        })
+    }
+}
+
+/// Weekly charging windows in local time, `utc_offset_minutes` ahead of UTC.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChargingSchedule {
+    pub utc_offset_minutes: i32,
+    pub windows: Vec<ChargingWindow>,
+}
+
+impl ChargingSchedule {
+    pub fn validate(&self) -> Result<(), ScheduleError> {
+        if self.windows.len() > MAX_WINDOWS {
+            return Err(ScheduleError::TooManyWindows {
+                windows: self.windows.len(),
+            });
+        }
+        if !(MIN_UTC_OFFSET_MINUTES..=MAX_UTC_OFFSET_MINUTES).contains(&self.utc_offset_minutes) {
+            return Err(ScheduleError::InvalidUtcOffset {
+                utc_offset_minutes: self.utc_offset_minutes,
+            });
+        }
+        for (window, charging_window) in self.windows.iter().enumerate() {
+            if charging_window.days.is_empty() {
+                return Err(ScheduleError::WindowWithoutDays { window });
+            } else if charging_window.start == charging_window.end {
+                return Err(ScheduleError::EmptyWindow { window });
+            } else if charging_window.charging_speed_w == 0 {
+                return Err(ScheduleError::InvalidChargingSpeed { window });
+            }
+        }
+        Ok(())
+    }
+
+    /// The first window open at `unix_time_s`, seconds since the Unix epoch in UTC.
+    pub fn active_window(&self, unix_time_s: u64) -> Option<&ChargingWindow> {
+        let local_minutes = (unix_time_s / 60) as i64 + self.utc_offset_minutes as i64;
+        let local_minutes = local_minutes.rem_euclid(MINUTES_PER_WEEK as i64) as u32;
+        let minute_of_week = (local_minutes + EPOCH_WEEKDAY * MINUTES_PER_DAY) % MINUTES_PER_WEEK;
+        self.windows
+            .iter()
+            .find(|window| window.contains(minute_of_week))
+    }
+}
+
+/// What the schedule did about the window it is in.
+#[derive(Debug, Default)]
+pub struct ScheduleState {
+    active_window: Option<ChargingWindow>,
+    /// Set by the manual charging commands, the schedule leaves charging alone until the next
+    /// window.
+    overridden: bool,
+    /// The schedule started the charging and stops it when the window closes.
+    started_charging: bool,
+}
+
+impl ScheduleState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands charging over to the manual commands until the window changes.
+    pub fn override_schedule(&mut self) {
+        self.overridden = true;
+        self.started_charging = false;
+    }
+
+    /// A window is open and no manual command took over.
+    pub fn is_active(&self) -> bool {
+        self.active_window.is_some() && !self.overridden
+    }
+}
+
+/// Loads the stored charging schedule, an empty one if none is stored or it is unusable.
+pub fn load_charging_schedule(storage: &mut dyn Storage) -> ChargingSchedule {
+    let schedule = match load_json::<ChargingSchedule>(storage, CHARGING_SCHEDULE_STORAGE_KEY) {
+        Ok(schedule) => schedule.unwrap_or_default(),
+        Err(error) => {
+            warn!("Stored charging schedule is unreadable, using none: {error}");
+            ChargingSchedule::default()
+        }
+    };
+    match schedule.validate() {
+        Ok(()) => schedule,
+        Err(error) => {
+            warn!("Stored charging schedule is invalid, using none: {error}");
+            ChargingSchedule::default()
+        }
+    }
+}
+
+pub fn store_charging_schedule(
+    storage: &mut dyn Storage,
+    schedule: &ChargingSchedule,
+) -> Result<()> {
+    store_json(storage, CHARGING_SCHEDULE_STORAGE_KEY, schedule)
+}
+
+/// Queues the schedule retained, so the backend sees what the device charges by.
+pub fn queue_charging_schedule(outbox: &Outbox, schedule: &ChargingSchedule) {
+    let schedule_json = serde_json::to_vec(schedule).expect("Charging schedule serializes to JSON");
+    outbox.push(
+        CHARGING_SCHEDULE_TOPIC,
+        QoS::AtLeastOnce,
+        true,
+        schedule_json,
+    );
+}
+
+/// Starts charging a connected car while a window is open and stops it when the window closes,
+/// unless a manual command took over or a departure plan or solar charging is in charge. Called
+/// periodically once the wall clock is set.
+pub fn apply_charging_schedule(context: &Context, unix_time_s: u64) -> Result<()> {
+    let mut charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    if context
+        .departure_plan_mutex
+        .lock()
+        .expect("Failed lock on departure_plan_mutex")
+        .is_some()
+    {
+        return Ok(());
+    }
+    let solar_mode = context
+        .solar_settings_rwlock
+        .read()
+        .expect("Failed read access on solar_settings_rwlock")
+        .mode;
+    let solar_overridden = context
+        .solar_state_mutex
+        .lock()
+        .expect("Failed lock on solar_state_mutex")
+        .is_overridden();
+    if solar_mode != SolarMode::Off && !solar_overridden {
+        return Ok(());
+    }
+    let active_window = context
+        .charging_schedule_rwlock
+        .read()
+        .expect("Failed read access on charging_schedule_rwlock")
+        .active_window(unix_time_s)
+        .cloned();
+    let mut schedule_state = context
+        .schedule_state_mutex
+        .lock()
+        .expect("Failed lock on schedule_state_mutex");
+
+    if active_window != schedule_state.active_window {
+        if active_window.is_none()
+            && schedule_state.started_charging
+            && charging_controller.state() == ChargingControllerState::Charging
+        {
+            charging_controller.stop_charging()?;
+            end_session(
+                context,
+                &charging_controller,
+                SessionEndReason::ScheduleEnded,
+            );
+            queue_status(context, &charging_controller);
+            info!("Charging window closed, charging stopped");
+        }
+        schedule_state.active_window = active_window.clone();
+        schedule_state.overridden = false;
+        schedule_state.started_charging =
+            schedule_state.started_charging && active_window.is_some();
+    }
+
+    let Some(window) = active_window else {
+        return Ok(());
+    };
+    if schedule_state.overridden {
+        return Ok(());
+    }
+    let Some(car) = charging_controller
+        .car_rwlock()
+        .map(|car_rwlock| *car_rwlock.read().expect("Failed read access on car_rwlock"))
+    else {
+        return Ok(());
+    };
+    let charging_speed_w = window.charging_speed_w.min(car.max_charging_speed_w);
+    let result = match charging_controller.state() {
+        ChargingControllerState::Connected if !car.is_fully_charged() => {
+            info!("Charging window open, charging with {charging_speed_w}w");
+            schedule_state.started_charging = true;
+            charging_controller
+                .start_charging(charging_speed_w)
+                .map(|()| begin_session(context, &charging_controller))
+        }
+        ChargingControllerState::Charging
+            if schedule_state.started_charging
+                && charging_controller.charging_speed_w() != Some(charging_speed_w) =>
+        {
+            info!("Next charging window, charging with {charging_speed_w}w");
+            charging_controller.change_charging_speed(charging_speed_w)
+        }
+        _ => return Ok(()),
+    };
+    if let Err(error) = result {
+        // Not retried every tick, the next window tries again.
+        schedule_state.override_schedule();
+        Err(error)?
+    }
+    queue_status(context, &charging_controller);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::{
+        car::Car,
+        charging_session::load_sessions,
+        handler_functions::{handle_start_charging, handle_stop_charging},
+    };
+
+    const HOUR: u64 = 3600;
+    const DAY: u64 = 24 * HOUR;
+    /// Unix time of the first Monday, 1970-01-05.
+    const MONDAY: u64 = 4 * DAY;
+
+    fn window(days: &[Weekday], start: &str, end: &str, charging_speed_w: u32) -> ChargingWindow {
+        ChargingWindow {
+            days: days.to_vec(),
+            start: TimeOfDay::try_from(start.to_string()).unwrap(),
+            end: TimeOfDay::try_from(end.to_string()).unwrap(),
+            charging_speed_w,
+        }
+    }
+
+    #[test]
+    fn parses_time_of_day() {
+        for (time, minutes) in [
+            ("00:00", Some(0)),
+            ("7:05", Some(425)),
+            ("23:59", Some(1439)),
+        ] {
+            assert_eq!(
+                TimeOfDay::try_from(time.to_string())
+                    .map(|time| time.minutes())
+                    .ok(),
+                minutes
+            );
+        }
+        for time in [
+            "24:00", "12:60", "+7:05", "-7:05", "7:+5", "007:05", "7:5", ":05", "7.05",
+        ] {
+            assert_eq!(
+                TimeOfDay::try_from(time.to_string()),
+                Err(ScheduleError::InvalidTime {
+                    time: time.to_string()
+                }),
+                "{time}"
+            );
+        }
+    }
+
+    #[test]
+    fn window_contains_minutes_of_its_days() {
+        use Weekday::*;
+        let minute = |day: u32, hour: u32, minute: u32| (day * 24 + hour) * 60 + minute;
+        for (window, minute_of_week, contained) in [
+            (
+                window(&[Monday], "10:00", "14:00", 1),
+                minute(0, 10, 0),
+                true,
+            ),
+            (
+                window(&[Monday], "10:00", "14:00", 1),
+                minute(0, 13, 59),
+                true,
+            ),
+            (
+                window(&[Monday], "10:00", "14:00", 1),
+                minute(0, 14, 0),
+                false,
+            ),
+            (
+                window(&[Monday], "10:00", "14:00", 1),
+                minute(1, 12, 0),
+                false,
+            ),
+            (
+                window(&[Monday, Wednesday], "10:00", "14:00", 1),
+                minute(2, 12, 0),
+                true,
+            ),
+            // Past midnight into the next day, but not into the day before.
+            (
+                window(&[Monday], "22:00", "06:00", 1),
+                minute(1, 5, 59),
+                true,
+            ),
+            (
+                window(&[Monday], "22:00", "06:00", 1),
+                minute(0, 5, 0),
+                false,
+            ),
+            // Past the end of the week into Monday.
+            (
+                window(&[Sunday], "22:00", "06:00", 1),
+                minute(6, 23, 0),
+                true,
+            ),
+            (
+                window(&[Sunday], "22:00", "06:00", 1),
+                minute(0, 1, 0),
+                true,
+            ),
+            (
+                window(&[Sunday], "22:00", "06:00", 1),
+                minute(0, 6, 0),
+                false,
+            ),
+        ] {
+            assert_eq!(
+                window.contains(minute_of_week),
+                contained,
+                "{window:?} at {minute_of_week}"
+            );
+        }
+    }
+
+    #[test]
+    fn finds_active_window_in_local_time() {
+        use Weekday::*;
+        for (utc_offset_minutes, window, unix_time_s, active) in [
+            // Unix time starts on a Thursday.
+            (0, window(&[Thursday], "10:00", "14:00", 1), 12 * HOUR, true),
+            (
+                0,
+                window(&[Wednesday], "10:00", "14:00", 1),
+                12 * HOUR,
+                false,
+            ),
+            (
+                0,
+                window(&[Monday], "10:00", "14:00", 1),
+                MONDAY + 12 * HOUR,
+                true,
+            ),
+            (
+                0,
+                window(&[Monday], "10:00", "14:00", 1),
+                MONDAY + 7 * DAY + 12 * HOUR,
+                true,
+            ),
+            (
+                0,
+                window(&[Monday], "22:00", "06:00", 1),
+                MONDAY + DAY + 3 * HOUR,
+                true,
+            ),
+            (
+                0,
+                window(&[Monday], "22:00", "06:00", 1),
+                MONDAY + DAY + 7 * HOUR,
+                false,
+            ),
+            (
+                0,
+                window(&[Sunday], "22:00", "06:00", 1),
+                MONDAY + HOUR,
+                true,
+            ),
+            (
+                0,
+                window(&[Sunday], "22:00", "06:00", 1),
+                MONDAY - HOUR,
+                true,
+            ),
+            // Ahead of UTC, Wednesday 23:30 UTC is already Thursday.
+            (
+                60,
+                window(&[Thursday], "00:00", "01:00", 1),
+                7 * DAY - HOUR / 2,
+                true,
+            ),
+            (
+                0,
+                window(&[Thursday], "00:00", "01:00", 1),
+                7 * DAY - HOUR / 2,
+                false,
+            ),
+            // Behind UTC, the first hour of Unix time is still Wednesday evening.
+            (
+                -300,
+                window(&[Wednesday], "19:00", "21:00", 1),
+                HOUR / 2,
+                true,
+            ),
+            (
+                -300,
+                window(&[Thursday], "00:00", "01:00", 1),
+                HOUR / 2,
+                false,
+            ),
+            (
+                -300,
+                window(&[Wednesday], "19:00", "21:00", 1),
+                2 * HOUR,
+                false,
+            ),
+        ] {
+            let schedule = ChargingSchedule {
+                utc_offset_minutes,
+                windows: vec![window],
+            };
+            assert_eq!(
+                schedule.active_window(unix_time_s).is_some(),
+                active,
+                "{schedule:?} at {unix_time_s}"
+            );
+        }
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum Step {
+        Tick(u64),
+        StartCharging,
+        StopCharging,
+    }
+
+    #[test]
+    fn schedule_opens_closes_and_yields_to_manual_commands() {
+        use ChargingControllerState::{Charging, Connected};
+        use Step::*;
+        let context = Context::in_memory();
+        context
+            .charging_controller_mutex
+            .lock()
+            .unwrap()
+            .connect_car(Arc::new(RwLock::new(Car::new(1000, 0, 100).unwrap())))
+            .unwrap();
+        *context.charging_schedule_rwlock.write().unwrap() = ChargingSchedule {
+            utc_offset_minutes: 0,
+            windows: vec![window(&[Weekday::Thursday], "10:00", "14:00", 150)],
+        };
+
+        for (step, state, charging_speed_w) in [
+            (Tick(9 * HOUR), Connected, None),
+            // Capped at the car's maximum.
+            (Tick(10 * HOUR), Charging, Some(100)),
+            (Tick(13 * HOUR), Charging, Some(100)),
+            (Tick(14 * HOUR), Connected, None),
+            (Tick(7 * DAY + 10 * HOUR), Charging, Some(100)),
+            // A manual stop holds until the next window.
+            (StopCharging, Connected, None),
+            (Tick(7 * DAY + 11 * HOUR), Connected, None),
+            (Tick(7 * DAY + 14 * HOUR), Connected, None),
+            (Tick(14 * DAY + 10 * HOUR), Charging, Some(100)),
+            // Manual charging is left alone when the window closes.
+            (StopCharging, Connected, None),
+            (StartCharging, Charging, Some(20)),
+            (Tick(14 * DAY + 14 * HOUR), Charging, Some(20)),
+        ] {
+            match step {
+                Tick(unix_time_s) => apply_charging_schedule(&context, unix_time_s).unwrap(),
+                StartCharging => handle_start_charging(
+                    serde_json::from_str(r#"{"charging_speed_w":20}"#).unwrap(),
+                    context.clone(),
+                )
+                .unwrap(),
+                StopCharging => handle_stop_charging(context.clone()).unwrap(),
+            }
+            let charging_controller = context.charging_controller_mutex.lock().unwrap();
+            assert_eq!(charging_controller.state(), state, "{step:?}");
+            assert_eq!(
+                charging_controller.charging_speed_w(),
+                charging_speed_w,
+                "{step:?}"
+            );
+        }
+
+        let sessions = load_sessions(&mut *context.storage_mutex.lock().unwrap(), 3).unwrap();
+        let end_reasons: Vec<SessionEndReason> =
+            sessions.iter().map(|session| session.end_reason).collect();
+        assert_eq!(
+            end_reasons,
+            [
+                SessionEndReason::Stopped,
+                SessionEndReason::Stopped,
+                SessionEndReason::ScheduleEnded
+            ]
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    charging_controller::ChargingController,
+    context::Context,
+    storage::{load_json, store_json, Storage},
+    wall_clock::unix_time_s,
+};
+
+/// Topic the list-sessions command publishes the sessions on.
+pub const SESSIONS_TOPIC: &str = "sessions";
+/// Storage key of the sequence number the next session is recorded with.
+pub const NEXT_SESSION_STORAGE_KEY: &str = "session_next";
+/// Number of sessions kept, the oldest is overwritten by the next one.
+pub const SESSION_SLOTS: u32 = 16;
+const SECONDS_PER_HOUR: f32 = 3600.0;
+
+/// Why a charging session ended.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionEndReason {
+    /// The stop-charging command.
+    Stopped,
+    FullyCharged,
+    ChargeLimit,
+    ScheduleEnded,
+    DepartureReached,
+    DeparturePassed,
+    SolarSurplusGone,
+    SolarSettingsChanged,
+}
+
+/// A finished charging session. The times are Unix seconds, `None` if the wall clock was not
+/// set yet.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SessionRecord {
+    pub sequence: u32,
+    pub car_id: Option<String>,
+    pub started_at: Option<u64>,
+    pub ended_at: Option<u64>,
+    pub duration_s: u64,
+    pub start_charge_wh: u32,
+    pub end_charge_wh: u32,
+    pub energy_wh: f32,
+    pub peak_power_w: f32,
+    pub average_power_w: f32,
+    pub end_reason: SessionEndReason,
+}
+
+/// The session of the car charging right now.
+#[derive(Debug)]
+pub struct ActiveSession {
+    car_id: Option<String>,
+    started_at: Option<u64>,
+    start_charge_wh: u32,
+    duration: Duration,
+    energy_wh: f32,
+    peak_power_w: f32,
+}
+
+impl ActiveSession {
+    /// Adds the power delivered during one tick.
+    pub fn record_power(&mut self, power_w: f32, elapsed: Duration) {
+        self.duration += elapsed;
+        self.energy_wh += power_w * elapsed.as_secs_f32() / SECONDS_PER_HOUR;
+        self.peak_power_w = self.peak_power_w.max(power_w);
+    }
+}
+
+pub(crate) fn session_storage_key(sequence: u32) -> String {
+    format!("session_{}", sequence % SESSION_SLOTS)
+}
+
+/// Starts the session of the car `charging_controller` just started charging. Locks the car
+/// registry, so it is called with `charging_controller_mutex` held.
+pub fn begin_session(context: &Context, charging_controller: &ChargingController) {
+    let Some(car_rwlock) = charging_controller.car_rwlock() else {
+        return;
+    };
+    let car_id = context
+        .car_registry_rwlock
+        .read()
+        .expect("Failed read access on car_registry_rwlock")
+        .id_of(car_rwlock)
+        .map(str::to_string);
+    let start_charge_wh = car_rwlock
+        .read()
+        .expect("Failed read access on car_rwlock")
+        .current_charge_wh();
+    *context
+        .charging_session_mutex
+        .lock()
+        .expect("Failed lock on charging_session_mutex") = Some(ActiveSession {
+        car_id,
+        started_at: unix_time_s(),
+        start_charge_wh,
+        duration: Duration::ZERO,
+        energy_wh: 0.0,
+        peak_power_w: 0.0,
+    });
+}
+
+/// Ends the session of the car `charging_controller` just stopped charging and records it. A
+/// session that cannot be stored is lost with a warning, charging stopped either way.
+pub fn end_session(
+    context: &Context,
+    charging_controller: &ChargingController,
+    end_reason: SessionEndReason,
+) {
+    let Some(session) = context
+        .charging_session_mutex
+        .lock()
+        .expect("Failed lock on charging_session_mutex")
+        .take()
+    else {
+        return;
+    };
+    let end_charge_wh = charging_controller
+        .car_rwlock()
+        .map(|car_rwlock| {
+            car_rwlock
+                .read()
+                .expect("Failed read access on car_rwlock")
+                .current_charge_wh()
+        })
+        .unwrap_or(session.start_charge_wh);
+    let duration_s = session.duration.as_secs_f32();
+    let mut record = SessionRecord {
+        sequence: 0,
+        car_id: session.car_id,
+        started_at: session.started_at,
+        ended_at: unix_time_s(),
+        duration_s: session.duration.as_secs(),
+        start_charge_wh: session.start_charge_wh,
+        end_charge_wh,
+        energy_wh: session.energy_wh,
+        peak_power_w: session.peak_power_w,
+        average_power_w: if duration_s > 0.0 {
+            session.energy_wh * SECONDS_PER_HOUR / duration_s
+        } else {
+            0.0
+        },
+        end_reason,
+    };
+    let mut storage = context
+        .storage_mutex
+        .lock()
+        .expect("Failed lock on storage_mutex");
+    match store_session(&mut *storage, &mut record) {
+        Ok(()) => info!(
+            "Charging session {} ended: {:?}",
+            record.sequence, record.end_reason
+        ),
+        Err(error) => warn!("Failed to record the charging session: {error}"),
+    }
+}
+
+/// Stores `record` in the next slot of the ring buffer and sets its sequence number.
+fn store_session(storage: &mut dyn Storage, record: &mut SessionRecord) -> Result<()> {
+    let sequence = load_json::<u32>(storage, NEXT_SESSION_STORAGE_KEY)?.unwrap_or(0);
+    record.sequence = sequence;
+    store_json(storage, &session_storage_key(sequence), record)?;
+    store_json(storage, NEXT_SESSION_STORAGE_KEY, &sequence.wrapping_add(1))
+}
+
+/// Loads up to `count` of the recorded sessions, the latest first. Unreadable sessions are
+/// skipped.
+pub fn load_sessions(storage: &mut dyn Storage, count: u32) -> Result<Vec<SessionRecord>> {
+    let next_sequence = load_json::<u32>(storage, NEXT_SESSION_STORAGE_KEY)?.unwrap_or(0);
+    let count = count.min(SESSION_SLOTS).min(next_sequence);
+    let mut sessions = Vec::new();
+    for sequence in (next_sequence - count..next_sequence).rev() {
+        match load_json::<SessionRecord>(storage, &session_storage_key(sequence)) {
+            Ok(Some(record)) => sessions.push(record),
+            Ok(None) => {}
+            Err(error) => warn!("Stored charging session {sequence} is unreadable: {error}"),
+        }
+    }
+    Ok(sessions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn record(energy_wh: f32) -> SessionRecord {
+        SessionRecord {
+            sequence: 0,
+            car_id: Some("van".to_string()),
+            started_at: None,
+            ended_at: None,
+            duration_s: 3600,
+            start_charge_wh: 0,
+            end_charge_wh: 10,
+            energy_wh,
+            peak_power_w: 10.0,
+            average_power_w: energy_wh,
+            end_reason: SessionEndReason::Stopped,
+        }
+    }
+
+    /// Storage with `count` sessions recorded, session `n` with `n` Wh.
+    fn recorded_sessions(count: u32) -> MemoryStorage {
+        let mut storage = MemoryStorage::new();
+        for sequence in 0..count {
+            store_session(&mut storage, &mut record(sequence as f32)).unwrap();
+        }
+        storage
+    }
+
+    fn sequences(storage: &mut MemoryStorage, count: u32) -> Vec<u32> {
+        load_sessions(storage, count)
+            .unwrap()
+            .iter()
+            .map(|record| record.sequence)
+            .collect()
+    }
+
+    #[test]
+    fn lists_latest_sessions_first() {
+        let mut storage = recorded_sessions(3);
+        assert_eq!(sequences(&mut storage, 3), [2, 1, 0]);
+        assert_eq!(sequences(&mut storage, 2), [2, 1]);
+        assert!(sequences(&mut storage, 0).is_empty());
+        assert_eq!(sequences(&mut storage, SESSION_SLOTS), [2, 1, 0]);
+        let sessions = load_sessions(&mut storage, 1).unwrap();
+        assert_eq!(sessions[0].energy_wh, 2.0);
+    }
+
+    #[test]
+    fn overwrites_oldest_session() {
+        let mut storage = recorded_sessions(SESSION_SLOTS + 4);
+        let expected: Vec<u32> = (4..SESSION_SLOTS + 4).rev().collect();
+        assert_eq!(sequences(&mut storage, SESSION_SLOTS), expected);
+        assert_eq!(sequences(&mut storage, u32::MAX), expected);
+        // The last session took the slot of the last overwritten one.
+        let slot = load_json::<SessionRecord>(&mut storage, &session_storage_key(3))
+            .unwrap()
+            .unwrap();
+        assert_eq!(slot.sequence, SESSION_SLOTS + 3);
+    }
+
+    #[test]
+    fn skips_unreadable_sessions() {
+        let mut storage = recorded_sessions(4);
+        storage.store(&session_storage_key(2), b"{\"sequ").unwrap();
+        assert_eq!(sequences(&mut storage, 4), [3, 1, 0]);
+    }
+
+    #[test]
+    fn empty_storage_has_no_sessions() {
+        let mut storage = MemoryStorage::new();
+        assert!(sequences(&mut storage, SESSION_SLOTS).is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{Error, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    calibration::CalibrationError, car_registry::CarRegistryError, charging_error::ChargingError,
+    charging_schedule::ScheduleError, context::Context, departure_charging::DepartureError,
+    mqtt::QoS, network_config::NetworkConfigError, solar_charging::SolarError,
+    topic_router::TopicRouter,
+};
+
+/// Appended to a command topic to get the topic its responses go to.
+pub const RESPONSE_TOPIC_SUFFIX: &str = "/response";
+/// Topic below the device's prefix that response topics given by clients are placed under.
+pub const CLIENT_RESPONSES_TOPIC: &str = "responses";
+
+/// Code of a command whose payload could not be parsed.
+pub const INVALID_PAYLOAD_CODE: u16 = 1;
+/// Code of a command that failed for a reason other than a [`CommandError`].
+pub const INTERNAL_ERROR_CODE: u16 = 2;
+
+/// Rejection of a command with a stable numeric code. Its serialized form, tagged with the
+/// kebab-case name of the variant, becomes the details of the response.
+pub trait CommandError: std::error::Error + Serialize + Send + Sync + 'static {
+    fn code(&self) -> u16;
+}
+
+/// Code and details of `error` if it is a `T`.
+type CommandErrorLookup = fn(&Error) -> Option<(u16, Option<serde_json::Value>)>;
+
+fn lookup<T: CommandError>(error: &Error) -> Option<(u16, Option<serde_json::Value>)> {
+    error.downcast_ref::<T>().map(|command_error| {
+        (
+            command_error.code(),
+            serde_json::to_value(command_error).ok(),
+        )
+    })
+}
+
+/// Every [`CommandError`] a handler can fail with. A type missing here is answered as an
+/// internal error, so each one is added when it is introduced.
+const COMMAND_ERRORS: [CommandErrorLookup; 7] = [
+    lookup::<ChargingError>,
+    lookup::<NetworkConfigError>,
+    lookup::<CarRegistryError>,
+    lookup::<ScheduleError>,
+    lookup::<DepartureError>,
+    lookup::<SolarError>,
+    lookup::<CalibrationError>,
+];
+
+/// Fields every command payload may carry next to its own data. The MQTT client runs MQTT 3.1.1,
+/// so `response_topic` stands in for the MQTT 5 response-topic property.
+#[derive(Deserialize, Debug, Default)]
+struct CommandEnvelope {
+    request_id: Option<String>,
+    response_topic: Option<String>,
+}
+
+/// Reply to a command that carried a request id or a response topic.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CommandResponse {
+    pub request_id: Option<String>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// The rejection with the offending values, if the charging controller, the car or the
+    /// network config refused.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl CommandResponse {
+    pub fn success(request_id: Option<String>) -> Self {
+        CommandResponse {
+            request_id,
+            success: true,
+            code: None,
+            message: None,
+            details: None,
+        }
+    }
+
+    pub fn failure(request_id: Option<String>, error: &Error) -> Self {
+        let (code, details) = COMMAND_ERRORS
+            .iter()
+            .find_map(|lookup| lookup(error))
+            .unwrap_or_else(|| {
+                if error.is::<serde_json::Error>() {
+                    (INVALID_PAYLOAD_CODE, None)
+                } else {
+                    (INTERNAL_ERROR_CODE, None)
+                }
+            });
+        CommandResponse {
+            request_id,
+            success: false,
+            code: Some(code),
+            message: Some(error.to_string()),
+            details,
+        }
+    }
+}
+
+/// Dispatches a command through `router` and queues the response on the context's outbox if
+/// the payload asked for one. Returns the result of the handler.
+pub fn handle_command(
+    router: &TopicRouter,
+    topic: &str,
+    data: &[u8],
+    context: Context,
+) -> Result<()> {
+    let envelope: CommandEnvelope = serde_json::from_slice(data).unwrap_or_default();
+    let outbox = context.outbox.clone();
+    let result = router.dispatch(topic, data, context);
+
+    if envelope.request_id.is_some() || envelope.response_topic.is_some() {
+        let response = match &result {
+            Ok(()) => CommandResponse::success(envelope.request_id),
+            Err(error) => CommandResponse::failure(envelope.request_id, error),
+        };
+        let response_topic = match envelope.response_topic {
+            Some(response_topic) if is_client_response_topic(&response_topic) => {
+                outbox.topic(&format!("{CLIENT_RESPONSES_TOPIC}/{response_topic}"))
+            }
+            response_topic => {
+                if let Some(response_topic) = response_topic {
+                    warn!("Response topic `{response_topic}` refused, responding on the default");
+                }
+                format!("{topic}{RESPONSE_TOPIC_SUFFIX}")
+            }
+        };
+        outbox.push_absolute(
+            &response_topic,
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&response)?,
+        );
+    }
+    result
+}
+
+/// A client's response topic names a topic below [`CLIENT_RESPONSES_TOPIC`], so a client cannot
+/// make the device publish anywhere else, e.g. on its retained status.
+fn is_client_response_topic(response_topic: &str) -> bool {
+    !response_topic.is_empty()
+        && !response_topic.starts_with('/')
+        && !response_topic.contains(['+', '#', '\0'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topic_router::Route;
+
+    fn succeed(_context: Context) -> Result<()> {
+        Ok(())
+    }
+
+    fn router() -> TopicRouter {
+        TopicRouter::new("/charging-controller/a1").route(Route::without_payload(
+            "do",
+            QoS::AtMostOnce,
+            succeed,
+        ))
+    }
+
+    fn responded_on(payload: &[u8]) -> String {
+        let context = Context::in_memory();
+        handle_command(
+            &router(),
+            "/charging-controller/a1/do",
+            payload,
+            context.clone(),
+        )
+        .unwrap();
+        let messages = context.outbox.drain();
+        assert_eq!(messages.len(), 1);
+        messages[0].topic.clone()
+    }
+
+    #[test]
+    fn responds_on_client_topic_below_prefix() {
+        let context = Context::in_memory();
+        assert_eq!(
+            responded_on(br#"{"request_id":"42","response_topic":"backend/replies/42"}"#),
+            context.outbox.topic("responses/backend/replies/42")
+        );
+    }
+
+    #[test]
+    fn refuses_client_topics_outside_prefix() {
+        for response_topic in [
+            "",
+            "/charging-controller/a1/status",
+            "replies/+",
+            "replies/#",
+        ] {
+            let payload = serde_json::json!({"request_id":"42","response_topic":response_topic});
+            assert_eq!(
+                responded_on(payload.to_string().as_bytes()),
+                "/charging-controller/a1/do/response",
+                "{response_topic}"
+            );
+        }
+    }
+
+    #[test]
+    fn looks_up_command_error_codes() {
+        let failure = |error: Error| CommandResponse::failure(None, &error);
+        let response = failure(CalibrationError::NoCurrentChange.into());
+        assert_eq!(response.code, Some(1105));
+        assert_eq!(
+            response.details,
+            Some(serde_json::json!({"error":"no-current-change"}))
+        );
+        let response = failure(ChargingError::NoCarConnected.into());
+        assert_eq!(response.code, Some(200));
+        let parse_error = serde_json::from_str::<u32>("x").unwrap_err();
+        assert_eq!(failure(parse_error.into()).code, Some(INVALID_PAYLOAD_CODE));
+        let response = failure(anyhow::anyhow!("Bus failed"));
+        assert_eq!(response.code, Some(INTERNAL_ERROR_CODE));
+        assert_eq!(response.details, None);
+    }
+
+    #[test]
+    fn responds_below_command_topic_by_default() {
+        let context = Context::in_memory();
+        handle_command(
+            &router(),
+            "/charging-controller/a1/do",
+            br#"{"request_id":"42"}"#,
+            context.clone(),
+        )
+        .unwrap();
+        let messages = context.outbox.drain();
+        assert_eq!(messages[0].topic, "/charging-controller/a1/do/response");
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&messages[0].payload).unwrap(),
+            serde_json::json!({"request_id":"42","success":true})
+        );
+    }
+}
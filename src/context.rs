@@ -1,11 +1,64 @@
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{atomic::AtomicBool, Arc, Mutex, RwLock};
 
 use crate::{
-    car::Car, charging_controller::ChargingController,
+    calibration::ChargingCalibration,
+    car_registry::CarRegistry,
+    charging_controller::ChargingController,
+    charging_mode::ChargingMode,
+    charging_schedule::{ChargingSchedule, ScheduleState},
+    charging_session::ActiveSession,
+    departure_charging::DeparturePlan,
+    mqtt::Outbox,
+    solar_charging::{SolarSettings, SolarState},
+    storage::Storage,
+    trip::TripState,
 };
 
 #[derive(Clone)]
 pub struct Context {
     pub charging_controller_mutex: Arc<Mutex<ChargingController>>,
-    pub car_rwlock: Arc<RwLock<Car>>,
+    /// Locked after `charging_controller_mutex` when both are needed.
+    pub car_registry_rwlock: Arc<RwLock<CarRegistry>>,
+    pub calibration_rwlock: Arc<RwLock<ChargingCalibration>>,
+    pub storage_mutex: Arc<Mutex<dyn Storage>>,
+    /// Set by the calibrate command and cleared by the hardware loop once it ran the sweep.
+    /// Nothing starts charging while it is set.
+    pub calibration_requested: Arc<AtomicBool>,
+    pub trip_state_mutex: Arc<Mutex<TripState>>,
+    pub charging_schedule_rwlock: Arc<RwLock<ChargingSchedule>>,
+    /// Locked after `charging_controller_mutex` when both are needed.
+    pub schedule_state_mutex: Arc<Mutex<ScheduleState>>,
+    /// Locked after `charging_controller_mutex`, takes precedence over the schedule while set.
+    pub departure_plan_mutex: Arc<Mutex<Option<DeparturePlan>>>,
+    pub solar_settings_rwlock: Arc<RwLock<SolarSettings>>,
+    /// Locked after `charging_controller_mutex` when both are needed.
+    pub solar_state_mutex: Arc<Mutex<SolarState>>,
+    /// Mode in charge of the charging speed as last published in the status.
+    pub charging_mode_mutex: Arc<Mutex<ChargingMode>>,
+    /// Locked after `charging_controller_mutex`, set while a car is charging.
+    pub charging_session_mutex: Arc<Mutex<Option<ActiveSession>>>,
+    pub outbox: Outbox,
+}
+
+#[cfg(test)]
+impl Context {
+    /// Context with nothing connected and an empty in-memory storage.
+    pub fn in_memory() -> Self {
+        Context {
+            charging_controller_mutex: Default::default(),
+            car_registry_rwlock: Default::default(),
+            calibration_rwlock: Default::default(),
+            storage_mutex: Arc::new(Mutex::new(crate::storage::MemoryStorage::new())),
+            calibration_requested: Default::default(),
+            trip_state_mutex: Arc::new(Mutex::new(TripState::Idle)),
+            charging_schedule_rwlock: Default::default(),
+            schedule_state_mutex: Default::default(),
+            departure_plan_mutex: Default::default(),
+            solar_settings_rwlock: Default::default(),
+            solar_state_mutex: Default::default(),
+            charging_mode_mutex: Default::default(),
+            charging_session_mutex: Default::default(),
+            outbox: Default::default(),
+        }
+    }
 }
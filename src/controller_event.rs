@@ -0,0 +1,43 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::mqtt::{MqttPublisher, QoS};
+
+pub const EVENTS_TOPIC: &str = "events";
+
+/// Things the controller did on its own, published for the backend.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ControllerEvent {
+    FullyCharged {
+        current_charge_wh: u32,
+    },
+    TripStarted {
+        energy_usage_w: u32,
+    },
+    TripFinished {
+        energy_usage_w: u32,
+        current_charge_wh: u32,
+    },
+    TripFailed {
+        energy_usage_w: u32,
+    },
+    DepartureTargetReached {
+        current_charge_wh: u32,
+    },
+    /// Even the car's maximum charging speed falls short of the departure target.
+    DepartureTargetUnreachable {
+        target_charge_wh: u32,
+        expected_charge_wh: u32,
+    },
+}
+
+pub async fn publish_event(
+    mqtt_client: &mut impl MqttPublisher,
+    event: &ControllerEvent,
+) -> Result<()> {
+    let event_json = serde_json::to_string(event)?;
+    mqtt_client
+        .publish(EVENTS_TOPIC, QoS::AtLeastOnce, false, event_json.as_bytes())
+        .await
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    charging_controller::{ChargingController, ChargingControllerState},
+    charging_mode::ChargingMode,
+    context::Context,
+    mqtt::QoS,
+};
+
+pub const STATUS_TOPIC: &str = "status";
+
+/// Current picture of the charging controller and its car, published retained so late
+/// subscribers get it straight away. The car fields are empty while no car is connected.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ControllerStatus {
+    pub state: ChargingControllerState,
+    pub mode: ChargingMode,
+    pub charging_speed_w: Option<u32>,
+    pub current_charge_wh: Option<u32>,
+    pub charging_capacity_wh: Option<u32>,
+    pub max_charging_speed_w: Option<u32>,
+    /// Charge the car counts as full at, its charge limit if it has one.
+    pub full_charge_wh: Option<u32>,
+    pub charge_limit_wh: Option<u32>,
+    pub is_fully_charged: Option<bool>,
+}
+
+impl ControllerStatus {
+    pub fn new(charging_controller: &ChargingController, mode: ChargingMode) -> Self {
+        let car = charging_controller
+            .car_rwlock()
+            .map(|car_rwlock| *car_rwlock.read().expect("Failed read access on car_rwlock"));
+        ControllerStatus {
+            state: charging_controller.state(),
+            mode,
+            charging_speed_w: charging_controller.charging_speed_w(),
+            current_charge_wh: car.map(|car| car.current_charge_wh()),
+            charging_capacity_wh: car.map(|car| car.charging_capacity_wh()),
+            max_charging_speed_w: car.map(|car| car.max_charging_speed_w),
+            full_charge_wh: car.map(|car| car.full_charge_wh()),
+            charge_limit_wh: car.and_then(|car| car.charge_limit_wh()),
+            is_fully_charged: car.map(|car| car.is_fully_charged()),
+        }
+    }
+}
+
+/// Queues the status of `charging_controller`. Called with the controller still locked after
+/// each transition, so statuses are queued in the order the transitions happened.
+pub fn queue_status(context: &Context, charging_controller: &ChargingController) {
+    let mode = *context
+        .charging_mode_mutex
+        .lock()
+        .expect("Failed lock on charging_mode_mutex");
+    let status_json = serde_json::to_vec(&ControllerStatus::new(charging_controller, mode))
+        .expect("Controller status serializes to JSON");
+    context
+        .outbox
+        .push(STATUS_TOPIC, QoS::AtLeastOnce, true, status_json);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::car::Car;
+
+    fn car_rwlock() -> Arc<RwLock<Car>> {
+        let mut car = Car::new(1000, 400, 100).unwrap();
+        car.set_charge_limit(Some(800)).unwrap();
+        Arc::new(RwLock::new(car))
+    }
+
+    fn status_json(
+        charging_controller: &ChargingController,
+        mode: ChargingMode,
+    ) -> serde_json::Value {
+        serde_json::to_value(ControllerStatus::new(charging_controller, mode)).unwrap()
+    }
+
+    #[test]
+    fn serializes_each_state() {
+        let mut charging_controller = ChargingController::new();
+        assert_eq!(
+            status_json(&charging_controller, ChargingMode::Manual),
+            json!({
+                "state": "Disconnected",
+                "mode": "manual",
+                "charging_speed_w": null,
+                "current_charge_wh": null,
+                "charging_capacity_wh": null,
+                "max_charging_speed_w": null,
+                "full_charge_wh": null,
+                "charge_limit_wh": null,
+                "is_fully_charged": null,
+            })
+        );
+
+        let car_rwlock = car_rwlock();
+        charging_controller.connect_car(car_rwlock.clone()).unwrap();
+        let connected = json!({
+            "state": "Connected",
+            "mode": "schedule",
+            "charging_speed_w": null,
+            "current_charge_wh": 400,
+            "charging_capacity_wh": 1000,
+            "max_charging_speed_w": 100,
+            "full_charge_wh": 800,
+            "charge_limit_wh": 800,
+            "is_fully_charged": false,
+        });
+        assert_eq!(
+            status_json(&charging_controller, ChargingMode::Schedule),
+            connected
+        );
+
+        charging_controller.start_charging(60).unwrap();
+        let mut charging = connected.clone();
+        charging["state"] = json!("Charging");
+        charging["mode"] = json!("solar");
+        charging["charging_speed_w"] = json!(60);
+        assert_eq!(
+            status_json(&charging_controller, ChargingMode::Solar),
+            charging
+        );
+
+        charging_controller.stop_charging().unwrap();
+        charging_controller.start_driving(car_rwlock).unwrap();
+        let mut driving = connected;
+        driving["state"] = json!("Driving");
+        driving["mode"] = json!("departure");
+        assert_eq!(
+            status_json(&charging_controller, ChargingMode::Departure),
+            driving
+        );
+    }
+}
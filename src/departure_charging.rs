@@ -0,0 +1,320 @@
+use std::fmt::{self, Display};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{
+    charging_controller::ChargingControllerState,
+    charging_schedule::TimeOfDay,
+    charging_session::{begin_session, end_session, SessionEndReason},
+    command_response::CommandError,
+    context::Context,
+    controller_event::{publish_event, ControllerEvent},
+    controller_status::queue_status,
+    mqtt::MqttPublisher,
+};
+
+const SECONDS_PER_HOUR: u64 = 3600;
+/// Charging faster than needed by up to this fraction of the needed speed is left alone, so the
+/// speed does not follow every Wh booked onto the car.
+const SPEED_TOLERANCE_DIVISOR: u32 = 20;
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "error", rename_all = "kebab-case")]
+pub enum DepartureError {
+    /// Exactly one of `target_charge_wh` and `target_percent` has to be given.
+    InvalidTarget,
+    InvalidTargetPercent {
+        percent: u32,
+    },
+    TargetAlreadyReached {
+        current_charge_wh: u32,
+    },
+    TargetAboveChargeLimit {
+        target_charge_wh: u32,
+        charge_limit_wh: u32,
+    },
+}
+
+impl CommandError for DepartureError {
+    fn code(&self) -> u16 {
+        match self {
+            DepartureError::InvalidTarget => 900,
+            DepartureError::InvalidTargetPercent { .. } => 901,
+            DepartureError::TargetAlreadyReached { .. } => 902,
+            DepartureError::TargetAboveChargeLimit { .. } => 903,
+        }
+    }
+}
+
+impl Display for DepartureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DepartureError::InvalidTarget => {
+                write!(
+                    f,
+                    "Either target_charge_wh or target_percent has to be given"
+                )
+            }
+            DepartureError::InvalidTargetPercent { percent } => {
+                write!(f, "Target of {percent}% is not between 1 and 100")
+            }
+            DepartureError::TargetAlreadyReached { current_charge_wh } => {
+                write!(f, "Car already holds the target with {current_charge_wh}wh")
+            }
+            DepartureError::TargetAboveChargeLimit {
+                target_charge_wh,
+                charge_limit_wh,
+            } => write!(
+                f,
+                "Target of {target_charge_wh}wh is above the car's charge limit of {charge_limit_wh}wh"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DepartureError {}
+
+/// Charge the connected car holds by the next `departure`, in local time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeparturePlan {
+    target_charge_wh: u32,
+    departure: TimeOfDay,
+    /// Set on the first tick with a wall clock.
+    deadline_unix_s: Option<u64>,
+    warned: bool,
+}
+
+impl DeparturePlan {
+    pub fn new(target_charge_wh: u32, departure: TimeOfDay) -> Self {
+        DeparturePlan {
+            target_charge_wh,
+            departure,
+            deadline_unix_s: None,
+            warned: false,
+        }
+    }
+
+    pub fn target_charge_wh(&self) -> u32 {
+        self.target_charge_wh
+    }
+}
+
+/// Minimum steady speed that books `remaining_wh` within `remaining_s`.
+fn required_speed_w(remaining_wh: u32, remaining_s: u64) -> u64 {
+    (remaining_wh as u64 * SECONDS_PER_HOUR).div_ceil(remaining_s)
+}
+
+/// Charges the connected car at the lowest steady speed that reaches the target by the
+/// departure, or at its maximum speed with a warning when even that falls short. The plan ends
+/// at the target or at the departure. Called periodically once the wall clock is set.
+pub async fn apply_departure_charging(
+    context: &Context,
+    unix_time_s: u64,
+    mqtt_client: &mut impl MqttPublisher,
+) -> Result<()> {
+    let event = {
+        let mut charging_controller = context
+            .charging_controller_mutex
+            .lock()
+            .expect("Failed lock on charging_controller_mutex");
+        let mut departure_plan = context
+            .departure_plan_mutex
+            .lock()
+            .expect("Failed lock on departure_plan_mutex");
+        let Some(plan) = departure_plan.as_mut() else {
+            return Ok(());
+        };
+        let utc_offset_minutes = context
+            .charging_schedule_rwlock
+            .read()
+            .expect("Failed read access on charging_schedule_rwlock")
+            .utc_offset_minutes;
+        let deadline_unix_s = *plan
+            .deadline_unix_s
+            .get_or_insert_with(|| plan.departure.next_after(unix_time_s, utc_offset_minutes));
+        let state = charging_controller.state();
+        let Some(car) = charging_controller
+            .car_rwlock()
+            .map(|car_rwlock| *car_rwlock.read().expect("Failed read access on car_rwlock"))
+        else {
+            return Ok(());
+        };
+        if state == ChargingControllerState::Driving {
+            return Ok(());
+        }
+
+        let current_charge_wh = car.current_charge_wh();
+        let target_charge_wh = plan.target_charge_wh;
+        let target_reached = current_charge_wh >= target_charge_wh;
+        // A charge limit lowered after the plan was made ends it short of the target.
+        let full_short_of_target = !target_reached && car.is_fully_charged();
+        if target_reached || full_short_of_target || unix_time_s >= deadline_unix_s {
+            if state == ChargingControllerState::Charging {
+                charging_controller.stop_charging()?;
+                let end_reason = if target_reached {
+                    SessionEndReason::DepartureReached
+                } else if full_short_of_target && car.is_charge_limited() {
+                    SessionEndReason::ChargeLimit
+                } else if full_short_of_target {
+                    SessionEndReason::FullyCharged
+                } else {
+                    SessionEndReason::DeparturePassed
+                };
+                end_session(context, &charging_controller, end_reason);
+                queue_status(context, &charging_controller);
+            }
+            *departure_plan = None;
+            if target_reached {
+                info!("Departure target reached with {current_charge_wh}wh");
+                Some(ControllerEvent::DepartureTargetReached { current_charge_wh })
+            } else if full_short_of_target {
+                warn!("Car full with {current_charge_wh}wh, short of the departure target");
+                Some(ControllerEvent::DepartureTargetUnreachable {
+                    target_charge_wh,
+                    expected_charge_wh: current_charge_wh,
+                })
+            } else {
+                warn!("Departure time passed with {current_charge_wh}wh");
+                None
+            }
+        } else {
+            let remaining_s = deadline_unix_s - unix_time_s;
+            let required_w =
+                required_speed_w(plan.target_charge_wh - current_charge_wh, remaining_s);
+            let charging_speed_w = required_w.min(car.max_charging_speed_w as u64) as u32;
+            let event = if required_w > car.max_charging_speed_w as u64 && !plan.warned {
+                plan.warned = true;
+                let expected_charge_wh = current_charge_wh as u64
+                    + car.max_charging_speed_w as u64 * remaining_s / SECONDS_PER_HOUR;
+                warn!("Departure target cannot be met, charging to {expected_charge_wh}wh");
+                Some(ControllerEvent::DepartureTargetUnreachable {
+                    target_charge_wh: plan.target_charge_wh,
+                    expected_charge_wh: expected_charge_wh as u32,
+                })
+            } else {
+                None
+            };
+
+            match (state, charging_controller.charging_speed_w()) {
+                (ChargingControllerState::Connected, _) => {
+                    info!("Charging with {charging_speed_w}w for the departure");
+                    charging_controller.start_charging(charging_speed_w)?;
+                    begin_session(context, &charging_controller);
+                    queue_status(context, &charging_controller);
+                }
+                (ChargingControllerState::Charging, Some(current_speed_w))
+                    if current_speed_w < charging_speed_w
+                        || current_speed_w
+                            > charging_speed_w + charging_speed_w / SPEED_TOLERANCE_DIVISOR =>
+                {
+                    charging_controller.change_charging_speed(charging_speed_w)?;
+                    queue_status(context, &charging_controller);
+                }
+                _ => {}
+            }
+            event
+        }
+    };
+    if let Some(event) = event {
+        publish_event(mqtt_client, &event).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::{
+        car::Car, charging_controller::ChargingControllerState,
+        handler_functions::handle_charge_for_departure, mqtt::RecordingPublisher,
+    };
+
+    /// Context with a 1000 Wh car holding 400 Wh connected.
+    fn connected_context(charge_limit_wh: Option<u32>) -> (Context, Arc<RwLock<Car>>) {
+        let context = Context::in_memory();
+        let mut car = Car::new(1000, 400, 100).unwrap();
+        car.set_charge_limit(charge_limit_wh).unwrap();
+        let car_rwlock = Arc::new(RwLock::new(car));
+        context
+            .charging_controller_mutex
+            .lock()
+            .unwrap()
+            .connect_car(car_rwlock.clone())
+            .unwrap();
+        (context, car_rwlock)
+    }
+
+    fn charge_for_departure(context: &Context, data: &str) -> Result<()> {
+        handle_charge_for_departure(serde_json::from_str(data).unwrap(), context.clone())
+    }
+
+    #[test]
+    fn refuses_target_above_charge_limit() {
+        let (context, _) = connected_context(Some(500));
+        let error =
+            charge_for_departure(&context, r#"{"target_charge_wh":900,"departure":"07:30"}"#)
+                .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<DepartureError>(),
+            Some(&DepartureError::TargetAboveChargeLimit {
+                target_charge_wh: 900,
+                charge_limit_wh: 500,
+            })
+        );
+        assert!(context.departure_plan_mutex.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn full_capacity_target_charges_until_full() {
+        let (context, _) = connected_context(None);
+        charge_for_departure(&context, r#"{"target_percent":100,"departure":"07:30"}"#).unwrap();
+        assert_eq!(
+            context
+                .departure_plan_mutex
+                .lock()
+                .unwrap()
+                .unwrap()
+                .target_charge_wh(),
+            990
+        );
+    }
+
+    #[test]
+    fn lowered_limit_ends_plan_unreached() {
+        let (context, car_rwlock) = connected_context(None);
+        charge_for_departure(&context, r#"{"target_charge_wh":900,"departure":"07:30"}"#).unwrap();
+        let mut mqtt_client = RecordingPublisher::default();
+        embassy_futures::block_on(apply_departure_charging(&context, 0, &mut mqtt_client)).unwrap();
+        assert_eq!(
+            context.charging_controller_mutex.lock().unwrap().state(),
+            ChargingControllerState::Charging
+        );
+
+        car_rwlock
+            .write()
+            .unwrap()
+            .set_charge_limit(Some(400))
+            .unwrap();
+        embassy_futures::block_on(apply_departure_charging(&context, 60, &mut mqtt_client))
+            .unwrap();
+        assert_eq!(
+            context.charging_controller_mutex.lock().unwrap().state(),
+            ChargingControllerState::Connected
+        );
+        assert!(context.departure_plan_mutex.lock().unwrap().is_none());
+        let event: serde_json::Value =
+            serde_json::from_slice(&mqtt_client.messages.last().unwrap().payload).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "event": "departure-target-unreachable",
+                "target_charge_wh": 900,
+                "expected_charge_wh": 400,
+            })
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use log::info;
+
+use crate::{
+    charging_session::{end_session, SessionEndReason},
+    context::Context,
+    controller_event::{publish_event, ControllerEvent},
+    controller_status::queue_status,
+    mqtt::MqttPublisher,
+};
+
+const SECONDS_PER_HOUR: f32 = 3600.0;
+/// Longest charging time whose booked charge a reboot can lose.
+const STORE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Integrates the energy delivered while charging and books it onto the car in whole Wh.
+/// Stops charging once the car counts as fully charged. The car registry is stored when that
+/// ends the session and every [`STORE_INTERVAL`] while charging.
+#[derive(Default)]
+pub struct EnergyMeter {
+    pending_wh: f32,
+    unstored: Duration,
+}
+
+impl EnergyMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the energy of one tick. Uses the measured power when there is a measurement,
+    /// otherwise the commanded charging speed.
+    pub async fn integrate(
+        &mut self,
+        context: &Context,
+        measured_power_w: Option<f32>,
+        elapsed: Duration,
+        mqtt_client: &mut impl MqttPublisher,
+    ) -> Result<()> {
+        let fully_charged_event = {
+            let mut charging_controller = context
+                .charging_controller_mutex
+                .lock()
+                .expect("Failed lock on charging_controller_mutex");
+            let Some(charging_speed_w) = charging_controller.charging_speed_w() else {
+                self.pending_wh = 0.0;
+                self.unstored = Duration::ZERO;
+                return Ok(());
+            };
+            self.unstored += elapsed;
+            let power_w = measured_power_w.unwrap_or(charging_speed_w as f32).max(0.0);
+            self.pending_wh += power_w * elapsed.as_secs_f32() / SECONDS_PER_HOUR;
+            if let Some(session) = context
+                .charging_session_mutex
+                .lock()
+                .expect("Failed lock on charging_session_mutex")
+                .as_mut()
+            {
+                session.record_power(power_w, elapsed);
+            }
+
+            let car_rwlock = charging_controller
+                .car_rwlock()
+                .expect("A charging controller has a car")
+                .clone();
+            let mut car = car_rwlock
+                .write()
+                .expect("Failed write access on car_rwlock");
+            let delivered_wh = self.pending_wh.floor();
+            if delivered_wh >= 1.0 {
+                self.pending_wh -= delivered_wh;
+                let new_charge_wh =
+                    (car.current_charge_wh() + delivered_wh as u32).min(car.charging_capacity_wh());
+                car.change_current_charge(new_charge_wh)?;
+            }
+
+            if car.is_fully_charged() {
+                let current_charge_wh = car.current_charge_wh();
+                let end_reason = if car.is_charge_limited() {
+                    SessionEndReason::ChargeLimit
+                } else {
+                    SessionEndReason::FullyCharged
+                };
+                drop(car);
+                charging_controller.stop_charging()?;
+                end_session(context, &charging_controller, end_reason);
+                queue_status(context, &charging_controller);
+                self.pending_wh = 0.0;
+                self.store_car_registry(context)?;
+                info!("Car fully charged with {current_charge_wh}wh");
+                Some(ControllerEvent::FullyCharged { current_charge_wh })
+            } else {
+                drop(car);
+                if self.unstored >= STORE_INTERVAL {
+                    self.store_car_registry(context)?;
+                }
+                None
+            }
+        };
+        if let Some(event) = fully_charged_event {
+            publish_event(mqtt_client, &event).await?;
+        }
+        Ok(())
+    }
+
+    fn store_car_registry(&mut self, context: &Context) -> Result<()> {
+        context
+            .car_registry_rwlock
+            .read()
+            .expect("Failed read access on car_registry_rwlock")
+            .store(
+                &mut *context
+                    .storage_mutex
+                    .lock()
+                    .expect("Failed lock on storage_mutex"),
+            )?;
+        self.unstored = Duration::ZERO;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car::Car,
+        car_registry::CarRegistry,
+        charging_session::{begin_session, load_sessions},
+        mqtt::RecordingPublisher,
+    };
+
+    /// Registers a car of 1000 Wh with `charge_limit_wh` as "van" and starts charging it at 100 W.
+    fn charging_context(charge_limit_wh: Option<u32>) -> Context {
+        let context = Context::in_memory();
+        let mut car = Car::new(1000, 0, 100).unwrap();
+        car.set_charge_limit(charge_limit_wh).unwrap();
+        let car_rwlock = {
+            let mut car_registry = context.car_registry_rwlock.write().unwrap();
+            car_registry.register("van", car).unwrap();
+            car_registry.get("van").unwrap()
+        };
+        let mut charging_controller = context.charging_controller_mutex.lock().unwrap();
+        charging_controller.connect_car(car_rwlock).unwrap();
+        charging_controller.start_charging(100).unwrap();
+        begin_session(&context, &charging_controller);
+        drop(charging_controller);
+        context
+    }
+
+    fn integrate(energy_meter: &mut EnergyMeter, context: &Context, elapsed: Duration) {
+        embassy_futures::block_on(energy_meter.integrate(
+            context,
+            None,
+            elapsed,
+            &mut RecordingPublisher::default(),
+        ))
+        .unwrap();
+    }
+
+    /// Charge of the car as the registry was last stored.
+    fn stored_charge_wh(context: &Context) -> u32 {
+        let car_registry = CarRegistry::load(&mut *context.storage_mutex.lock().unwrap()).unwrap();
+        car_registry
+            .get("van")
+            .map(|car_rwlock| car_rwlock.read().unwrap().current_charge_wh())
+            .unwrap_or(0)
+    }
+
+    /// Charges in ticks of a minute until the car counts as full and returns why the session
+    /// ended.
+    fn charge_until_full(context: &Context) -> SessionEndReason {
+        let mut energy_meter = EnergyMeter::new();
+        while context
+            .charging_controller_mutex
+            .lock()
+            .unwrap()
+            .charging_speed_w()
+            .is_some()
+        {
+            integrate(&mut energy_meter, context, Duration::from_secs(60));
+        }
+        let sessions = load_sessions(&mut *context.storage_mutex.lock().unwrap(), 1).unwrap();
+        sessions[0].end_reason
+    }
+
+    #[test]
+    fn ends_session_fully_charged() {
+        // The battery counts as full 10 Wh short of its capacity, a limit above that is no limit.
+        for (charge_limit_wh, charge_wh) in [(None, 991), (Some(1000), 1000), (Some(990), 991)] {
+            let context = charging_context(charge_limit_wh);
+            assert_eq!(charge_until_full(&context), SessionEndReason::FullyCharged);
+            assert_eq!(stored_charge_wh(&context), charge_wh);
+        }
+    }
+
+    #[test]
+    fn ends_session_at_charge_limit() {
+        let context = charging_context(Some(800));
+        assert_eq!(charge_until_full(&context), SessionEndReason::ChargeLimit);
+        // The last tick books what it delivered, up to 1.67 Wh past the limit.
+        assert_eq!(stored_charge_wh(&context), 801);
+    }
+
+    #[test]
+    fn stores_charge_at_interval_while_charging() {
+        let context = charging_context(None);
+        let mut energy_meter = EnergyMeter::new();
+        // 100 W for a minute books 1 Wh and keeps 0.67 Wh pending.
+        for _ in 0..4 {
+            integrate(&mut energy_meter, &context, Duration::from_secs(60));
+        }
+        assert_eq!(stored_charge_wh(&context), 0);
+        integrate(&mut energy_meter, &context, Duration::from_secs(60));
+        assert_eq!(stored_charge_wh(&context), 8);
+    }
+}
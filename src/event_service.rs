@@ -3,9 +3,13 @@ use std::io::{Error, ErrorKind};
 use anyhow::Result;
 use esp_idf_svc::{mqtt::client::EventPayload, sys::EspError};
 
-use crate::{context::Context, handle_event_implementation::handle_event_implementation};
+use crate::{command_response::handle_command, context::Context, topic_router::TopicRouter};
 
-pub fn handle_event<'a>(event_payload: EventPayload<'a, EspError>, context: Context) -> Result<()> {
+pub fn handle_event(
+    event_payload: EventPayload<'_, EspError>,
+    router: &TopicRouter,
+    context: Context,
+) -> Result<()> {
     match event_payload {
         EventPayload::Received {
             id: _,
@@ -13,7 +17,7 @@ pub fn handle_event<'a>(event_payload: EventPayload<'a, EspError>, context: Cont
             data,
             details: _,
         } => match topic {
-            Some(definitely_topic) => handle_event_implementation(definitely_topic, data, context)?,
+            Some(definitely_topic) => handle_command(router, definitely_topic, data, context)?,
             None => Err(Error::new(
                 ErrorKind::InvalidData,
                 "Received message: Topic not defined.",
@@ -1,24 +1,102 @@
-use std::io::{Error, ErrorKind};
-
-use anyhow::Result;
-
 use crate::{
-    context::Context,
     handler_functions::{
-        handle_change_charging_speed, handle_start_charging, handle_start_trip,
-        handle_stop_charging,
+        handle_calibrate, handle_change_charging_speed, handle_charge_for_departure,
+        handle_connect_car, handle_disconnect_car, handle_list_cars, handle_list_sessions,
+        handle_register_car, handle_remove_car, handle_set_calibration, handle_set_charge_limit,
+        handle_set_charging_schedule, handle_set_network_config, handle_set_solar_charging,
+        handle_start_charging, handle_start_trip, handle_stop_charging, handle_update_car,
     },
+    mqtt::QoS,
+    topic_router::{Route, TopicRouter},
 };
 
-pub fn handle_event_implementation<'a>(topic: &str, data: &[u8], context: Context) -> Result<()> {
-    match topic {
-        "/charging-controller/start-charging" => handle_start_charging(data, context),
-        "/charging-controller/change-charging-speed" => handle_change_charging_speed(data, context),
-        "/charging-controller/stop-charging" => handle_stop_charging(data, context),
-        "/charging-controller/start-trip" => handle_start_trip(data, context),
-        _ => {
-            let message = format!("Topic: {topic} not available");
-            Err(Error::new(ErrorKind::InvalidData, message))?
-        }
-    }
+/// Commands the charging controller accepts, below `prefix`.
+pub fn command_router(prefix: &str) -> TopicRouter {
+    TopicRouter::new(prefix)
+        .route(Route::new(
+            "register-car",
+            QoS::AtLeastOnce,
+            handle_register_car,
+        ))
+        .route(Route::new(
+            "update-car",
+            QoS::AtLeastOnce,
+            handle_update_car,
+        ))
+        .route(Route::new(
+            "remove-car",
+            QoS::AtLeastOnce,
+            handle_remove_car,
+        ))
+        .route(Route::new(
+            "set-charge-limit",
+            QoS::AtLeastOnce,
+            handle_set_charge_limit,
+        ))
+        .route(Route::without_payload(
+            "list-cars",
+            QoS::AtMostOnce,
+            handle_list_cars,
+        ))
+        .route(Route::new(
+            "connect-car",
+            QoS::AtMostOnce,
+            handle_connect_car,
+        ))
+        .route(Route::without_payload(
+            "disconnect-car",
+            QoS::AtMostOnce,
+            handle_disconnect_car,
+        ))
+        .route(Route::new(
+            "start-charging",
+            QoS::AtMostOnce,
+            handle_start_charging,
+        ))
+        .route(Route::new(
+            "change-charging-speed",
+            QoS::AtMostOnce,
+            handle_change_charging_speed,
+        ))
+        .route(Route::without_payload(
+            "stop-charging",
+            QoS::AtMostOnce,
+            handle_stop_charging,
+        ))
+        .route(Route::new("start-trip", QoS::AtMostOnce, handle_start_trip))
+        .route(Route::new(
+            "set-calibration",
+            QoS::AtMostOnce,
+            handle_set_calibration,
+        ))
+        .route(Route::without_payload(
+            "calibrate",
+            QoS::AtMostOnce,
+            handle_calibrate,
+        ))
+        .route(Route::new(
+            "charge-for-departure",
+            QoS::AtLeastOnce,
+            handle_charge_for_departure,
+        ))
+        .route(Route::new(
+            "set-charging-schedule",
+            QoS::AtLeastOnce,
+            handle_set_charging_schedule,
+        ))
+        .route(Route::new(
+            "set-solar-charging",
+            QoS::AtLeastOnce,
+            handle_set_solar_charging,
+        ))
+        .route(Route::with_optional_payload(
+            "list-sessions",
+            QoS::AtMostOnce,
+            handle_list_sessions,
+        ))
+        .route(Route::new(
+            "set-network-config",
+            QoS::AtLeastOnce,
+            handle_set_network_config,
+        ))
 }
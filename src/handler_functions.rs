@@ -1,7 +1,36 @@
+use std::sync::{atomic::Ordering, Arc};
+
 use anyhow::Result;
+use log::info;
 use serde::Deserialize;
 
-use crate::context::Context;
+use crate::{
+    calibration::{ChargingCalibration, CALIBRATION_STORAGE_KEY},
+    car::Car,
+    car_registry::{CarRegistry, CarRegistryError},
+    charging_controller::ChargingControllerState,
+    charging_error::ChargingError,
+    charging_mode::refresh_charging_mode,
+    charging_schedule::{
+        queue_charging_schedule, store_charging_schedule, ChargingSchedule, TimeOfDay,
+    },
+    charging_session::{
+        begin_session, end_session, load_sessions, SessionEndReason, SESSIONS_TOPIC, SESSION_SLOTS,
+    },
+    context::Context,
+    controller_status::queue_status,
+    departure_charging::{DepartureError, DeparturePlan},
+    mqtt::QoS,
+    network_config::{
+        load_network_config, store_network_config, NetworkConfigError, NetworkConfigUpdate,
+    },
+    solar_charging::{store_solar_settings, SolarSettings},
+    storage::store_json,
+    trip::TripState,
+};
+
+/// Topic the list-cars command publishes the registered cars on.
+pub const CARS_TOPIC: &str = "cars";
 
 #[derive(Deserialize, Debug)]
 pub struct ChargingEventData {
@@ -9,45 +38,536 @@ pub struct ChargingEventData {
 }
 
 #[derive(Deserialize, Debug)]
-struct StartTripEventData {
+pub struct CarEventData {
+    car_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CarProfileEventData {
+    car_id: String,
+    #[serde(flatten)]
+    car: Car,
+}
+
+/// Neither limit removes the car's charge limit.
+#[derive(Deserialize, Debug)]
+pub struct ChargeLimitEventData {
+    car_id: String,
+    charge_limit_wh: Option<u32>,
+    charge_limit_percent: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StartTripEventData {
     energy_usage_w: u32,
+    /// Car to send on the trip if none is connected.
+    car_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DepartureEventData {
+    target_charge_wh: Option<u32>,
+    target_percent: Option<u32>,
+    /// Local time of day the car leaves at, the next time the clock shows it.
+    departure: TimeOfDay,
+}
+
+/// Without a count all recorded sessions are listed.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ListSessionsEventData {
+    count: u32,
+}
+
+impl Default for ListSessionsEventData {
+    fn default() -> Self {
+        ListSessionsEventData {
+            count: SESSION_SLOTS,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetNetworkConfigEventData {
+    token: String,
+    #[serde(flatten)]
+    update: NetworkConfigUpdate,
+}
+
+pub fn handle_register_car(car_event_data: CarProfileEventData, context: Context) -> Result<()> {
+    let mut car_registry = context
+        .car_registry_rwlock
+        .write()
+        .expect("Failed write access on car_registry_rwlock");
+    car_registry.register(&car_event_data.car_id, car_event_data.car)?;
+    store_car_registry(&car_registry, &context)?;
+    info!("Car {} registered", car_event_data.car_id);
+    Ok(())
+}
+
+pub fn handle_update_car(car_event_data: CarProfileEventData, context: Context) -> Result<()> {
+    let charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    let mut car_registry = context
+        .car_registry_rwlock
+        .write()
+        .expect("Failed write access on car_registry_rwlock");
+    car_registry.update(
+        &car_event_data.car_id,
+        car_event_data.car,
+        &charging_controller,
+    )?;
+    store_car_registry(&car_registry, &context)?;
+    info!("Car {} updated", car_event_data.car_id);
+    Ok(())
+}
+
+pub fn handle_remove_car(car_event_data: CarEventData, context: Context) -> Result<()> {
+    let charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    let mut car_registry = context
+        .car_registry_rwlock
+        .write()
+        .expect("Failed write access on car_registry_rwlock");
+    car_registry.remove(&car_event_data.car_id, &charging_controller)?;
+    store_car_registry(&car_registry, &context)?;
+    info!("Car {} removed", car_event_data.car_id);
+    Ok(())
+}
+
+pub fn handle_set_charge_limit(
+    charge_limit_event_data: ChargeLimitEventData,
+    context: Context,
+) -> Result<()> {
+    let mut charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    let car_registry = context
+        .car_registry_rwlock
+        .read()
+        .expect("Failed read access on car_registry_rwlock");
+    let car_rwlock = car_registry.get(&charge_limit_event_data.car_id)?;
+    let (charge_limit_wh, is_fully_charged) = {
+        let mut car = car_rwlock
+            .write()
+            .expect("Failed write access on car_rwlock");
+        let charge_limit_wh = match (
+            charge_limit_event_data.charge_limit_wh,
+            charge_limit_event_data.charge_limit_percent,
+        ) {
+            (None, None) => None,
+            (Some(charge_limit_wh), None) if charge_limit_wh > 0 => Some(charge_limit_wh),
+            (None, Some(percent)) if (1..=100).contains(&percent) => {
+                Some((car.charging_capacity_wh() as u64 * percent as u64 / 100) as u32)
+            }
+            _ => Err(CarRegistryError::InvalidChargeLimit)?,
+        };
+        car.set_charge_limit(charge_limit_wh)?;
+        (charge_limit_wh, car.is_fully_charged())
+    };
+    store_car_registry(&car_registry, &context)?;
+    // A limit the car already holds ends its charging.
+    let is_charging_car = charging_controller.state() == ChargingControllerState::Charging
+        && charging_controller
+            .car_rwlock()
+            .is_some_and(|charging_car| Arc::ptr_eq(charging_car, &car_rwlock));
+    if is_charging_car && is_fully_charged {
+        charging_controller.stop_charging()?;
+        end_session(
+            &context,
+            &charging_controller,
+            SessionEndReason::ChargeLimit,
+        );
+        info!("Charge limit reached, charging stopped");
+    }
+    queue_status(&context, &charging_controller);
+    info!(
+        "Charge limit of car {} set to {charge_limit_wh:?}",
+        charge_limit_event_data.car_id
+    );
+    Ok(())
+}
+
+pub fn handle_list_cars(context: Context) -> Result<()> {
+    let cars = context
+        .car_registry_rwlock
+        .read()
+        .expect("Failed read access on car_registry_rwlock")
+        .list();
+    context.outbox.push(
+        CARS_TOPIC,
+        QoS::AtLeastOnce,
+        false,
+        serde_json::to_vec(&cars)?,
+    );
+    Ok(())
+}
+
+pub fn handle_connect_car(car_event_data: CarEventData, context: Context) -> Result<()> {
+    let mut charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    let car_rwlock = context
+        .car_registry_rwlock
+        .read()
+        .expect("Failed read access on car_registry_rwlock")
+        .get(&car_event_data.car_id)?;
+    charging_controller.connect_car(car_rwlock)?;
+    context
+        .solar_state_mutex
+        .lock()
+        .expect("Failed lock on solar_state_mutex")
+        .clear_override();
+    refresh_charging_mode(&context);
+    queue_status(&context, &charging_controller);
+    info!("Car {} connected", car_event_data.car_id);
+    Ok(())
 }
 
-pub fn handle_start_charging(data: &[u8], context: Context) -> Result<()> {
-    let charging_event_data: ChargingEventData = serde_json::from_slice(data)?;
+pub fn handle_disconnect_car(context: Context) -> Result<()> {
     let mut charging_controller = context
         .charging_controller_mutex
         .lock()
         .expect("Failed lock on charging_controller_mutex");
+    charging_controller.disconnect_car()?;
+    *context
+        .departure_plan_mutex
+        .lock()
+        .expect("Failed lock on departure_plan_mutex") = None;
+    queue_status(&context, &charging_controller);
+    // Keeps the charge the car got while it was plugged in.
+    store_car_registry(
+        &context
+            .car_registry_rwlock
+            .read()
+            .expect("Failed read access on car_registry_rwlock"),
+        &context,
+    )?;
+    Ok(())
+}
+
+pub fn handle_start_charging(
+    charging_event_data: ChargingEventData,
+    context: Context,
+) -> Result<()> {
+    let mut charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    refuse_while_calibrating(&context)?;
     charging_controller.start_charging(charging_event_data.charging_speed_w)?;
+    begin_session(&context, &charging_controller);
+    take_over_charging(&context);
+    queue_status(&context, &charging_controller);
     Ok(())
 }
 
-pub fn handle_change_charging_speed(data: &[u8], context: Context) -> Result<()> {
-    let charging_event_data: ChargingEventData = serde_json::from_slice(data)?;
+pub fn handle_change_charging_speed(
+    charging_event_data: ChargingEventData,
+    context: Context,
+) -> Result<()> {
     let mut charging_controller = context
         .charging_controller_mutex
         .lock()
         .expect("Failed lock on charging_controller_mutex");
     charging_controller.change_charging_speed(charging_event_data.charging_speed_w)?;
+    take_over_charging(&context);
+    queue_status(&context, &charging_controller);
     Ok(())
 }
 
-pub fn handle_stop_charging(_data: &[u8], context: Context) -> Result<()> {
+pub fn handle_stop_charging(context: Context) -> Result<()> {
     let mut charging_controller = context
         .charging_controller_mutex
         .lock()
         .expect("Failed lock on charging_controller_mutex");
     charging_controller.stop_charging()?;
+    end_session(&context, &charging_controller, SessionEndReason::Stopped);
+    take_over_charging(&context);
+    queue_status(&context, &charging_controller);
+    Ok(())
+}
+
+pub fn handle_start_trip(
+    start_trip_event_data: StartTripEventData,
+    context: Context,
+) -> Result<()> {
+    let energy_usage_w = start_trip_event_data.energy_usage_w;
+    let mut charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    let car_rwlock = {
+        let car_registry = context
+            .car_registry_rwlock
+            .read()
+            .expect("Failed read access on car_registry_rwlock");
+        match (
+            charging_controller.car_rwlock(),
+            start_trip_event_data.car_id,
+        ) {
+            (Some(connected), Some(car_id)) => {
+                if !Arc::ptr_eq(connected, &car_registry.get(&car_id)?) {
+                    Err(CarRegistryError::OtherCarConnected { car_id })?
+                }
+                connected.clone()
+            }
+            (Some(connected), None) => connected.clone(),
+            (None, Some(car_id)) => car_registry.get(&car_id)?,
+            (None, None) => Err(ChargingError::NoCarConnected)?,
+        }
+    };
+    let current_charge_wh = car_rwlock
+        .read()
+        .expect("Failed read access on car_rwlock")
+        .current_charge_wh();
+    if energy_usage_w > current_charge_wh {
+        Err(ChargingError::NotEnoughChargeForTrip {
+            energy_usage_w,
+            current_charge_wh,
+        })?
+    }
+    charging_controller.start_driving(car_rwlock)?;
+    queue_status(&context, &charging_controller);
+    *context
+        .trip_state_mutex
+        .lock()
+        .expect("Failed lock on trip_state_mutex") = TripState::Requested { energy_usage_w };
+    info!("Trip requested with energy usage: {energy_usage_w}wh");
+    Ok(())
+}
+
+pub fn handle_set_calibration(calibration: ChargingCalibration, context: Context) -> Result<()> {
+    {
+        let mut storage = context
+            .storage_mutex
+            .lock()
+            .expect("Failed lock on storage_mutex");
+        store_json(&mut *storage, CALIBRATION_STORAGE_KEY, &calibration)?;
+    }
+    *context
+        .calibration_rwlock
+        .write()
+        .expect("Failed write access on calibration_rwlock") = calibration;
+    info!("Charging calibration updated");
+    Ok(())
+}
+
+/// The sweep drives the wiper itself, so charging waits until it finished.
+fn refuse_while_calibrating(context: &Context) -> Result<(), ChargingError> {
+    if context.calibration_requested.load(Ordering::SeqCst) {
+        Err(ChargingError::ChargeWhileCalibrating)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn handle_calibrate(context: Context) -> Result<()> {
+    let charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    if charging_controller.charging_speed_w().is_some() {
+        Err(ChargingError::CalibrateWhileCharging)?
+    }
+    context.calibration_requested.store(true, Ordering::SeqCst);
+    info!("Calibration requested");
     Ok(())
 }
 
-pub fn handle_start_trip(data: &[u8], context: Context) -> Result<()> {
-    let start_trip_event_data: StartTripEventData = serde_json::from_slice(data)?;
-    /* let &mut hardware_controller = context.hardware_controller;
-    hardware_controller
-        .start_trip(timer, start_trip_event_data.energy_usage_w)
-        .await?;
-     */
+pub fn handle_set_charging_schedule(schedule: ChargingSchedule, context: Context) -> Result<()> {
+    schedule.validate()?;
+    {
+        let mut storage = context
+            .storage_mutex
+            .lock()
+            .expect("Failed lock on storage_mutex");
+        store_charging_schedule(&mut *storage, &schedule)?;
+    }
+    queue_charging_schedule(&context.outbox, &schedule);
+    info!(
+        "Charging schedule updated with {} windows",
+        schedule.windows.len()
+    );
+    *context
+        .charging_schedule_rwlock
+        .write()
+        .expect("Failed write access on charging_schedule_rwlock") = schedule;
     Ok(())
 }
+
+pub fn handle_charge_for_departure(
+    departure_event_data: DepartureEventData,
+    context: Context,
+) -> Result<()> {
+    let charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    if charging_controller.state() == ChargingControllerState::Driving {
+        Err(ChargingError::CarDriving)?
+    }
+    refuse_while_calibrating(&context)?;
+    let car = *charging_controller
+        .car_rwlock()
+        .ok_or(ChargingError::NoCarConnected)?
+        .read()
+        .expect("Failed read access on car_rwlock");
+    let target_charge_wh = match (
+        departure_event_data.target_charge_wh,
+        departure_event_data.target_percent,
+    ) {
+        (Some(target_charge_wh), None) => target_charge_wh,
+        (None, Some(percent)) if (1..=100).contains(&percent) => {
+            (car.charging_capacity_wh() as u64 * percent as u64 / 100) as u32
+        }
+        (None, Some(percent)) => Err(DepartureError::InvalidTargetPercent { percent })?,
+        _ => Err(DepartureError::InvalidTarget)?,
+    };
+    if target_charge_wh > car.charging_capacity_wh() {
+        Err(ChargingError::ChargeExceedsCapacity {
+            charge_wh: target_charge_wh,
+            charging_capacity_wh: car.charging_capacity_wh(),
+        })?
+    }
+    if car.is_charge_limited() && target_charge_wh > car.full_charge_wh() {
+        Err(DepartureError::TargetAboveChargeLimit {
+            target_charge_wh,
+            charge_limit_wh: car.full_charge_wh(),
+        })?
+    }
+    // Charging stops short of the capacity, so a target up to it means a full car.
+    let target_charge_wh = target_charge_wh.min(car.full_charge_wh());
+    if car.current_charge_wh() >= target_charge_wh {
+        Err(DepartureError::TargetAlreadyReached {
+            current_charge_wh: car.current_charge_wh(),
+        })?
+    }
+    take_over_charging(&context);
+    *context
+        .departure_plan_mutex
+        .lock()
+        .expect("Failed lock on departure_plan_mutex") = Some(DeparturePlan::new(
+        target_charge_wh,
+        departure_event_data.departure,
+    ));
+    refresh_charging_mode(&context);
+    queue_status(&context, &charging_controller);
+    info!(
+        "Charging to {target_charge_wh}wh by {}",
+        String::from(departure_event_data.departure)
+    );
+    Ok(())
+}
+
+pub fn handle_set_solar_charging(settings: SolarSettings, context: Context) -> Result<()> {
+    settings.validate()?;
+    let mut charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    {
+        let mut storage = context
+            .storage_mutex
+            .lock()
+            .expect("Failed lock on storage_mutex");
+        store_solar_settings(&mut *storage, &settings)?;
+    }
+    *context
+        .solar_settings_rwlock
+        .write()
+        .expect("Failed write access on solar_settings_rwlock") = settings;
+    // Solar charging starts over with the new settings.
+    let solar_state = std::mem::take(
+        &mut *context
+            .solar_state_mutex
+            .lock()
+            .expect("Failed lock on solar_state_mutex"),
+    );
+    if solar_state.started_charging()
+        && charging_controller.state() == ChargingControllerState::Charging
+    {
+        charging_controller.stop_charging()?;
+        end_session(
+            &context,
+            &charging_controller,
+            SessionEndReason::SolarSettingsChanged,
+        );
+    }
+    refresh_charging_mode(&context);
+    queue_status(&context, &charging_controller);
+    info!("Solar charging set to {:?}", settings.mode);
+    Ok(())
+}
+
+pub fn handle_list_sessions(
+    list_sessions_event_data: ListSessionsEventData,
+    context: Context,
+) -> Result<()> {
+    let sessions = load_sessions(
+        &mut *context
+            .storage_mutex
+            .lock()
+            .expect("Failed lock on storage_mutex"),
+        list_sessions_event_data.count,
+    )?;
+    context.outbox.push(
+        SESSIONS_TOPIC,
+        QoS::AtLeastOnce,
+        false,
+        serde_json::to_vec(&sessions)?,
+    );
+    Ok(())
+}
+
+pub fn handle_set_network_config(
+    event_data: SetNetworkConfigEventData,
+    context: Context,
+) -> Result<()> {
+    let mut storage = context
+        .storage_mutex
+        .lock()
+        .expect("Failed lock on storage_mutex");
+    let network_config = load_network_config(&mut *storage);
+    if !network_config.is_authorized(&event_data.token) {
+        Err(NetworkConfigError::Unauthorized)?
+    }
+    let network_config = network_config.updated(event_data.update)?;
+    store_network_config(&mut *storage, &network_config)?;
+    info!("Network config updated, applied after restart");
+    Ok(())
+}
+
+fn store_car_registry(car_registry: &CarRegistry, context: &Context) -> Result<()> {
+    let mut storage = context
+        .storage_mutex
+        .lock()
+        .expect("Failed lock on storage_mutex");
+    car_registry.store(&mut *storage)
+}
+
+/// Manual charging commands override the schedule and solar charging and cancel the departure
+/// plan.
+fn take_over_charging(context: &Context) {
+    context
+        .schedule_state_mutex
+        .lock()
+        .expect("Failed lock on schedule_state_mutex")
+        .override_schedule();
+    context
+        .solar_state_mutex
+        .lock()
+        .expect("Failed lock on solar_state_mutex")
+        .override_solar();
+    *context
+        .departure_plan_mutex
+        .lock()
+        .expect("Failed lock on departure_plan_mutex") = None;
+    refresh_charging_mode(context);
+}
@@ -1,49 +1,200 @@
-use std::time::Duration;
-
-use esp_idf_svc::{
-    hal::{gpio::PinDriver, i2c::I2cDriver, peripherals::Peripherals},
-    timer::EspAsyncTimer,
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use embedded_hal::{blocking::i2c, digital::v2::OutputPin};
 use log::info;
-use shared_bus::I2cProxy;
 
-use crate::tpl_potentiometer::TPLPotentiometer;
+use crate::{
+    async_timer::AsyncTimer, calibration::ChargingCalibration, tpl_potentiometer::TPLPotentiometer,
+};
 
 static TIME_IN_SECONDS_PER_WATT: u32 = 100;
-static EXPECTED_VOLAGE: f32 = 4.5;
 
-#[derive(Clone)]
-pub struct HardwareController<'a> {
-    pub tpl_potentiometer: TPLPotentiometer<I2cProxy<'a, std::sync::Mutex<I2cDriver<'static>>>>,
+/// Owns the actuators of the board: the TPL potentiometer of the charging path and the output
+/// pin driving the trip motor. Both are handed in once at construction. Each actuator sits
+/// behind its own lock, so a trip can run while the charging path keeps being regulated.
+pub struct HardwareController<I2C, P> {
+    tpl_potentiometer_mutex: Mutex<TPLPotentiometer<I2C>>,
+    trip_motor_pin_mutex: Mutex<P>,
+    calibration_rwlock: Arc<RwLock<ChargingCalibration>>,
 }
 
-impl<'a> HardwareController<'a> {
-    pub async fn start_trip(
-        &mut self,
-        esp_async_timer: &mut EspAsyncTimer,
-        energy_usage_w: u32,
-    ) -> Result<()> {
+impl<I2C, E, P> HardwareController<I2C, P>
+where
+    I2C: i2c::Write<Error = E> + i2c::Read<Error = E>,
+    E: Debug,
+    P: OutputPin,
+    P::Error: Debug,
+{
+    pub fn new(
+        tpl_potentiometer: TPLPotentiometer<I2C>,
+        trip_motor_pin: P,
+        calibration_rwlock: Arc<RwLock<ChargingCalibration>>,
+    ) -> Self {
+        HardwareController {
+            tpl_potentiometer_mutex: Mutex::new(tpl_potentiometer),
+            trip_motor_pin_mutex: Mutex::new(trip_motor_pin),
+            calibration_rwlock,
+        }
+    }
+
+    pub async fn start_trip(&self, timer: &mut impl AsyncTimer, energy_usage_w: u32) -> Result<()> {
         let trip_duration = energy_usage_w * TIME_IN_SECONDS_PER_WATT;
-        let peripherals = Peripherals::take()?;
-        let mut trip_pin = PinDriver::output(peripherals.pins.gpio4)?;
-        trip_pin.set_high()?;
+        self.trip_motor_pin_mutex
+            .lock()
+            .expect("Failed lock on trip_motor_pin_mutex")
+            .set_high()
+            .map_err(|error| anyhow!("Failed to activate motor: {error:?}"))?;
         info!("Motor activated for {}s", trip_duration);
-        esp_async_timer
-            .after(Duration::from_secs(trip_duration as u64))
-            .await?;
-        trip_pin.set_low()?;
+        let waited = timer.after(Duration::from_secs(trip_duration as u64)).await;
+        // The motor has to stop even when the wait was cut short.
+        self.trip_motor_pin_mutex
+            .lock()
+            .expect("Failed lock on trip_motor_pin_mutex")
+            .set_low()
+            .map_err(|error| anyhow!("Failed to stop motor: {error:?}"))?;
         info!("Motor stopped");
+        waited
+    }
+
+    pub fn set_wiper_step(&self, wiper_step: u8) -> Result<()> {
+        self.tpl_potentiometer_mutex
+            .lock()
+            .expect("Failed lock on tpl_potentiometer_mutex")
+            .set_wiper_step(wiper_step)
+            .map_err(|error| anyhow!("Failed to set wiper step: {error}"))
+    }
+
+    /// Sets the wiper step and reads it back to confirm the potentiometer took it.
+    pub fn set_wiper_step_verified(&self, wiper_step: u8) -> Result<()> {
+        self.tpl_potentiometer_mutex
+            .lock()
+            .expect("Failed lock on tpl_potentiometer_mutex")
+            .set_wiper_step_verified(wiper_step)
+            .map_err(|error| anyhow!("Failed to set wiper step: {error}"))
+    }
+
+    /// Moves the wiper to the step the charging calibration maps `charging_speed_w` to.
+    pub async fn set_charging_speed(&self, charging_speed_w: u32) -> Result<()> {
+        let wiper_step = self
+            .calibration_rwlock
+            .read()
+            .expect("Failed read access on calibration_rwlock")
+            .wiper_step_for_power(charging_speed_w as f32);
+        self.set_wiper_step(wiper_step)?;
+        info!("Charging speed {charging_speed_w}w set as wiper step {wiper_step}");
         Ok(())
     }
 
-    /// Data approximation of spec sheet: I = 901.07 / R^(0.99)
-    pub async fn set_charging_speed(&mut self, charging_speed_w: u32) -> Result<()> {
-        let charging_speed_a = charging_speed_w as f32 / EXPECTED_VOLAGE;
-        let charging_resistance_kohm = (901.07 / charging_speed_a).log(0.99) * 1000.0;
-        self.tpl_potentiometer
-            .set_resistance(charging_resistance_kohm)?;
+    /// Moves the wiper to the lowest current of the calibration. The charging output has no off
+    /// state, this is as low as it goes.
+    pub fn set_lowest_charging_current(&self) -> Result<()> {
+        let wiper_step = self
+            .calibration_rwlock
+            .read()
+            .expect("Failed read access on calibration_rwlock")
+            .wiper_step_for_power(0.0);
+        self.set_wiper_step(wiper_step)?;
+        info!("Charging output set to its lowest current at wiper step {wiper_step}");
         Ok(())
     }
+
+    /// Charging speed the current wiper position delivers according to the calibration.
+    pub fn charging_speed_w(&self) -> Result<f32> {
+        let wiper_step = self
+            .tpl_potentiometer_mutex
+            .lock()
+            .expect("Failed lock on tpl_potentiometer_mutex")
+            .wiper_step()
+            .map_err(|error| anyhow!("Failed to read wiper step: {error}"))?;
+        Ok(self
+            .calibration_rwlock
+            .read()
+            .expect("Failed read access on calibration_rwlock")
+            .power_for_wiper_step(wiper_step))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        i2c::TPL_ADDRESS,
+        i2c_simulator::SimulatedI2CBus,
+        mock_hardware::{MockClock, MockTimer, PinChange, PinLevel, RecordingOutputPin},
+    };
+
+    fn hardware_controller(
+        clock: &MockClock,
+    ) -> (
+        HardwareController<SimulatedI2CBus, RecordingOutputPin>,
+        RecordingOutputPin,
+    ) {
+        let trip_motor_pin = RecordingOutputPin::new(clock.clone());
+        let hardware_controller = HardwareController::new(
+            TPLPotentiometer::new(SimulatedI2CBus::new(), TPL_ADDRESS),
+            trip_motor_pin.clone(),
+            Default::default(),
+        );
+        (hardware_controller, trip_motor_pin)
+    }
+
+    fn change(at_s: u64, level: PinLevel) -> PinChange {
+        PinChange {
+            at: Duration::from_secs(at_s),
+            level,
+        }
+    }
+
+    #[test]
+    fn trip_drives_motor_for_its_duration() {
+        let clock = MockClock::new();
+        let (hardware_controller, trip_motor_pin) = hardware_controller(&clock);
+        let mut timer = MockTimer::new(clock.clone());
+
+        embassy_futures::block_on(hardware_controller.start_trip(&mut timer, 2)).unwrap();
+        assert_eq!(timer.waits(), [Duration::from_secs(200)]);
+        assert_eq!(
+            trip_motor_pin.changes(),
+            [change(0, PinLevel::High), change(200, PinLevel::Low)]
+        );
+    }
+
+    #[test]
+    fn trips_can_be_repeated() {
+        let clock = MockClock::new();
+        let (hardware_controller, trip_motor_pin) = hardware_controller(&clock);
+        let mut timer = MockTimer::new(clock.clone());
+
+        embassy_futures::block_on(hardware_controller.start_trip(&mut timer, 2)).unwrap();
+        embassy_futures::block_on(hardware_controller.start_trip(&mut timer, 1)).unwrap();
+        assert_eq!(
+            trip_motor_pin.changes(),
+            [
+                change(0, PinLevel::High),
+                change(200, PinLevel::Low),
+                change(200, PinLevel::High),
+                change(300, PinLevel::Low),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_wait_stops_motor() {
+        let clock = MockClock::new();
+        let (hardware_controller, trip_motor_pin) = hardware_controller(&clock);
+        let mut timer = MockTimer::new(clock.clone());
+        timer.set_failing(true);
+
+        assert!(embassy_futures::block_on(hardware_controller.start_trip(&mut timer, 2)).is_err());
+        assert_eq!(
+            trip_motor_pin.changes(),
+            [change(0, PinLevel::High), change(0, PinLevel::Low)]
+        );
+        assert_eq!(trip_motor_pin.level(), PinLevel::Low);
+    }
 }
@@ -1,52 +1,58 @@
-use std::time::Duration;
+use std::fmt::Debug;
 
-use esp_idf_svc::hal::gpio::AnyIOPin;
-use esp_idf_svc::hal::i2c::{I2c, I2cConfig, I2cDriver};
-use esp_idf_svc::hal::peripheral::Peripheral;
-use esp_idf_svc::hal::units::Hertz;
+#[cfg(feature = "esp")]
+use esp_idf_svc::hal::{
+    gpio::AnyIOPin,
+    i2c::{I2c, I2cConfig, I2cDriver},
+    peripheral::Peripheral,
+    units::Hertz,
+};
 
-use anyhow::Result;
-use esp_idf_svc::mqtt::client::{EspAsyncMqttClient, QoS};
-use esp_idf_svc::timer::EspAsyncTimer;
+use anyhow::{anyhow, Result};
+use embedded_hal::blocking::i2c;
 use ina219::INA219;
 use lazy_static::lazy_static;
 use log::info;
-use serde::Serialize;
-use shared_bus::I2cProxy;
+use serde::{Deserialize, Serialize};
 
+use crate::mqtt::{MqttPublisher, QoS};
 use crate::tpl_potentiometer::TPLPotentiometer;
 
-const POWER_INA_219_ADDRESS: u8 = 0x42;
-const SOLAR_INA_219_ADDRESS: u8 = 0x40;
-const TPL_ADDRESS: u8 = 0x2E;
+pub const POWER_INA_219_ADDRESS: u8 = 0x42;
+pub const SOLAR_INA_219_ADDRESS: u8 = 0x40;
+pub const TPL_ADDRESS: u8 = 0x2E;
 const INA_219_MAX_EXPECTED_CURRENT: f32 = 2.0;
-const INA_219_POWER_FACTOR: i16 = 20;
+pub const INA_219_POWER_FACTOR: i16 = 20;
+pub const INA_219_CALIBRATION: u16 = 6711;
 
 lazy_static! {
-    static ref CURRENT_LSB: f32 = INA_219_MAX_EXPECTED_CURRENT / (2.0_f32.powf(15.0));
+    pub static ref CURRENT_LSB: f32 = INA_219_MAX_EXPECTED_CURRENT / (2.0_f32.powf(15.0));
 }
 
-pub struct I2CDevices<'a> {
-    pub power_ina_219: INA219<I2cProxy<'a, std::sync::Mutex<I2cDriver<'static>>>>,
-    pub solar_ina_219: INA219<I2cProxy<'a, std::sync::Mutex<I2cDriver<'static>>>>,
-    pub tpl_potentiometer: TPLPotentiometer<I2cProxy<'a, std::sync::Mutex<I2cDriver<'static>>>>,
+pub struct I2CDevices<I2C> {
+    pub power_ina_219: INA219<I2C>,
+    pub solar_ina_219: INA219<I2C>,
+    pub tpl_potentiometer: TPLPotentiometer<I2C>,
 }
 
-impl<'a> I2CDevices<'a> {
-    pub fn new(
-        i2c_proxy: &shared_bus::I2cProxy<
-            'a,
-            std::sync::Mutex<esp_idf_svc::hal::i2c::I2cDriver<'static>>,
-        >,
-    ) -> Result<Self> {
-        println!("Setting up I2C bus.");
+impl<I2C, E> I2CDevices<I2C>
+where
+    I2C: i2c::Write<Error = E> + i2c::Read<Error = E> + Clone,
+    E: Debug,
+{
+    pub fn new(i2c_proxy: &I2C) -> Result<Self> {
+        info!("Setting up I2C bus.");
 
         let mut power_ina_219 = INA219::new(i2c_proxy.clone(), POWER_INA_219_ADDRESS);
         let mut solar_ina_219 = INA219::new(i2c_proxy.clone(), SOLAR_INA_219_ADDRESS);
         let tpl_potentiometer = TPLPotentiometer::new(i2c_proxy.clone(), TPL_ADDRESS);
 
-        power_ina_219.calibrate(6711)?;
-        solar_ina_219.calibrate(6711)?;
+        power_ina_219
+            .calibrate(INA_219_CALIBRATION)
+            .map_err(|error| anyhow!("Failed to calibrate power INA219: {error:?}"))?;
+        solar_ina_219
+            .calibrate(INA_219_CALIBRATION)
+            .map_err(|error| anyhow!("Failed to calibrate solar INA219: {error:?}"))?;
 
         let i2c_devices = Self {
             power_ina_219,
@@ -56,60 +62,69 @@ impl<'a> I2CDevices<'a> {
         Ok(i2c_devices)
     }
 
-    pub async fn write_mqtt_messages(
+    /// Reads both INA219s once and publishes their stats. Returns the stats of the solar panel.
+    pub async fn publish_stats(
         &mut self,
-        esp_async_timer: &mut EspAsyncTimer,
-        mqtt_client: &mut EspAsyncMqttClient,
-    ) -> Result<()> {
-        loop {
-            info!("--- POWER INA MQTT ---");
-            let power_ina_stats = build_ina_stats(&mut self.power_ina_219)?;
-            info!("{:?}", power_ina_stats);
-            let power_ina_stats_json = serde_json::to_string(&power_ina_stats)?;
-            mqtt_client
-                .publish(
-                    "/wall-plug/stats",
-                    QoS::AtMostOnce,
-                    false,
-                    power_ina_stats_json.as_bytes(),
-                )
-                .await?;
-            info!("--- SOLAR INA MQTT ---");
-            let solar_ina_stats = build_ina_stats(&mut self.solar_ina_219)?;
-            info!("{:?}", solar_ina_stats);
-            let solar_ina_stats_json = serde_json::to_string(&solar_ina_stats)?;
-            mqtt_client
-                .publish(
-                    "/solar-panel/stats",
-                    QoS::AtMostOnce,
-                    false,
-                    solar_ina_stats_json.as_bytes(),
-                )
-                .await?;
-            esp_async_timer.after(Duration::from_millis(1000)).await?;
-        }
+        mqtt_client: &mut impl MqttPublisher,
+    ) -> Result<INA219Stats> {
+        info!("--- POWER INA MQTT ---");
+        let power_ina_stats = build_ina_stats(&mut self.power_ina_219)?;
+        info!("{:?}", power_ina_stats);
+        let power_ina_stats_json = serde_json::to_string(&power_ina_stats)?;
+        mqtt_client
+            .publish(
+                "wall-plug/stats",
+                QoS::AtMostOnce,
+                false,
+                power_ina_stats_json.as_bytes(),
+            )
+            .await?;
+        info!("--- SOLAR INA MQTT ---");
+        let solar_ina_stats = build_ina_stats(&mut self.solar_ina_219)?;
+        info!("{:?}", solar_ina_stats);
+        let solar_ina_stats_json = serde_json::to_string(&solar_ina_stats)?;
+        mqtt_client
+            .publish(
+                "solar-panel/stats",
+                QoS::AtMostOnce,
+                false,
+                solar_ina_stats_json.as_bytes(),
+            )
+            .await?;
+        Ok(solar_ina_stats)
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct INA219Stats {
-    shunt_voltage: i16,
-    power: i16,
-    current: f32,
-    bus_voltage: u16,
+    pub shunt_voltage: i16,
+    pub power: i16,
+    pub current: f32,
+    pub bus_voltage: u16,
 }
 
-fn build_ina_stats<'a>(
-    ina_219: &mut INA219<I2cProxy<'a, std::sync::Mutex<I2cDriver<'static>>>>,
-) -> Result<INA219Stats> {
+impl INA219Stats {
+    /// Power from current and bus voltage, in W.
+    pub fn power_w(&self) -> f32 {
+        self.current * self.bus_voltage as f32 / 1000.0
+    }
+}
+
+pub fn build_ina_stats<I2C, E>(ina_219: &mut INA219<I2C>) -> Result<INA219Stats>
+where
+    I2C: i2c::Write<Error = E> + i2c::Read<Error = E>,
+    E: Debug,
+{
+    let read_error = |error: E| anyhow!("Failed to read INA219: {error:?}");
     Ok(INA219Stats {
-        shunt_voltage: 10 * ina_219.shunt_voltage()?,
-        power: INA_219_POWER_FACTOR * ina_219.power()?,
-        current: *CURRENT_LSB * (ina_219.current()? as f32),
-        bus_voltage: ina_219.voltage()?,
+        shunt_voltage: 10 * ina_219.shunt_voltage().map_err(read_error)?,
+        power: INA_219_POWER_FACTOR * ina_219.power().map_err(read_error)?,
+        current: *CURRENT_LSB * (ina_219.current().map_err(read_error)? as f32),
+        bus_voltage: ina_219.voltage().map_err(read_error)?,
     })
 }
 
+#[cfg(feature = "esp")]
 pub fn i2c_master_init<'d>(
     i2c: impl Peripheral<P = impl I2c> + 'd,
     sda: AnyIOPin,
@@ -120,3 +135,117 @@ pub fn i2c_master_init<'d>(
     let driver = I2cDriver::new(i2c, sda, scl, &config)?;
     Ok(driver)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::i2c_simulator::{
+        INA219Register, SimulatedI2CBus, SimulatedI2CError, SimulatedINA219,
+    };
+    use crate::mqtt::RecordingPublisher;
+
+    fn simulated_devices() -> (
+        I2CDevices<SimulatedI2CBus>,
+        SimulatedINA219,
+        SimulatedINA219,
+    ) {
+        let bus = SimulatedI2CBus::new();
+        let power_ina_219 = SimulatedINA219::new();
+        let solar_ina_219 = SimulatedINA219::new();
+        bus.attach(POWER_INA_219_ADDRESS, power_ina_219.clone());
+        bus.attach(SOLAR_INA_219_ADDRESS, solar_ina_219.clone());
+        let i2c_devices = I2CDevices::new(&bus).expect("Simulated devices set up");
+        (i2c_devices, power_ina_219, solar_ina_219)
+    }
+
+    #[test]
+    fn calibrates_both_ina219s() {
+        let (_, power_ina_219, solar_ina_219) = simulated_devices();
+        assert_eq!(power_ina_219.calibration(), INA_219_CALIBRATION);
+        assert_eq!(solar_ina_219.calibration(), INA_219_CALIBRATION);
+    }
+
+    #[test]
+    fn scales_raw_registers() {
+        let (mut i2c_devices, power_ina_219, _) = simulated_devices();
+        power_ina_219.set_shunt_voltage(-120);
+        power_ina_219.set_bus_voltage_mv(4500);
+        power_ina_219.set_current(16384);
+        power_ina_219.set_power(50);
+
+        let stats = build_ina_stats(&mut i2c_devices.power_ina_219).unwrap();
+        assert_eq!(
+            stats,
+            INA219Stats {
+                shunt_voltage: -1200,
+                power: 50 * INA_219_POWER_FACTOR,
+                current: 16384.0 * *CURRENT_LSB,
+                bus_voltage: 4500,
+            }
+        );
+        assert_eq!(stats.current, 1.0);
+        assert_eq!(stats.power_w(), 4.5);
+    }
+
+    #[test]
+    fn publishes_both_ina219s() {
+        let (mut i2c_devices, power_ina_219, solar_ina_219) = simulated_devices();
+        power_ina_219.set_power(10);
+        solar_ina_219.set_current(8192);
+        solar_ina_219.set_bus_voltage_mv(6000);
+        let mut mqtt_client = RecordingPublisher::default();
+
+        let solar_stats =
+            embassy_futures::block_on(i2c_devices.publish_stats(&mut mqtt_client)).unwrap();
+        assert_eq!(solar_stats.power_w(), 3.0);
+        let topics: Vec<&str> = mqtt_client
+            .messages
+            .iter()
+            .map(|message| message.topic.as_str())
+            .collect();
+        assert_eq!(topics, ["wall-plug/stats", "solar-panel/stats"]);
+        let power_stats: INA219Stats =
+            serde_json::from_slice(&mqtt_client.messages[0].payload).unwrap();
+        assert_eq!(power_stats.power, 200);
+    }
+
+    #[test]
+    fn fails_on_nack() {
+        let (mut i2c_devices, power_ina_219, _) = simulated_devices();
+        power_ina_219.set_nack(true);
+        let error = build_ina_stats(&mut i2c_devices.power_ina_219).unwrap_err();
+        assert!(error.to_string().contains(&format!(
+            "{:?}",
+            SimulatedI2CError::Nack {
+                address: POWER_INA_219_ADDRESS
+            }
+        )));
+        let mut mqtt_client = RecordingPublisher::default();
+        assert!(embassy_futures::block_on(i2c_devices.publish_stats(&mut mqtt_client)).is_err());
+        assert!(mqtt_client.messages.is_empty());
+
+        power_ina_219.set_nack(false);
+        assert!(build_ina_stats(&mut i2c_devices.power_ina_219).is_ok());
+    }
+
+    #[test]
+    fn reports_stuck_register() {
+        let (mut i2c_devices, power_ina_219, _) = simulated_devices();
+        power_ina_219.stick(INA219Register::Power, 7);
+        power_ina_219.set_power(60);
+        assert_eq!(
+            build_ina_stats(&mut i2c_devices.power_ina_219)
+                .unwrap()
+                .power,
+            7 * INA_219_POWER_FACTOR
+        );
+
+        power_ina_219.unstick(INA219Register::Power);
+        assert_eq!(
+            build_ina_stats(&mut i2c_devices.power_ina_219)
+                .unwrap()
+                .power,
+            60 * INA_219_POWER_FACTOR
+        );
+    }
+}
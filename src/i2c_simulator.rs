@@ -0,0 +1,354 @@
+//! Simulated I2C bus with register-level device models, so the drivers on top of
+//! `embedded_hal::blocking::i2c` can be exercised on the host. Devices are shared handles: the
+//! test keeps a clone to program values and inject faults while the driver talks to the bus.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    sync::{Arc, Mutex},
+};
+
+use embedded_hal::blocking::i2c;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SimulatedI2CError {
+    /// No device acknowledged the address or the transferred data.
+    Nack { address: u8 },
+}
+
+impl Display for SimulatedI2CError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimulatedI2CError::Nack { address } => write!(f, "NACK from address {address:#04x}"),
+        }
+    }
+}
+
+impl std::error::Error for SimulatedI2CError {}
+
+/// Refusal of a transfer by a simulated device, reported on the bus as a NACK.
+#[derive(Clone, Copy, Debug)]
+pub struct Nack;
+
+pub trait SimulatedDevice: Send {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Nack>;
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Nack>;
+}
+
+#[derive(Clone, Default)]
+pub struct SimulatedI2CBus {
+    devices: Arc<Mutex<HashMap<u8, Box<dyn SimulatedDevice>>>>,
+}
+
+impl SimulatedI2CBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn attach(&self, address: u8, device: impl SimulatedDevice + 'static) {
+        self.devices
+            .lock()
+            .expect("Failed lock on simulated devices")
+            .insert(address, Box::new(device));
+    }
+
+    fn transfer(
+        &self,
+        address: u8,
+        transfer: impl FnOnce(&mut dyn SimulatedDevice) -> Result<(), Nack>,
+    ) -> Result<(), SimulatedI2CError> {
+        let mut devices = self
+            .devices
+            .lock()
+            .expect("Failed lock on simulated devices");
+        let device = devices
+            .get_mut(&address)
+            .ok_or(SimulatedI2CError::Nack { address })?;
+        transfer(device.as_mut()).map_err(|Nack| SimulatedI2CError::Nack { address })
+    }
+}
+
+impl i2c::Write for SimulatedI2CBus {
+    type Error = SimulatedI2CError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.transfer(address, |device| device.write(bytes))
+    }
+}
+
+impl i2c::Read for SimulatedI2CBus {
+    type Error = SimulatedI2CError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.transfer(address, |device| device.read(buffer))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum INA219Register {
+    Configuration = 0x00,
+    ShuntVoltage = 0x01,
+    BusVoltage = 0x02,
+    Power = 0x03,
+    Current = 0x04,
+    Calibration = 0x05,
+}
+
+impl INA219Register {
+    fn from_pointer(pointer: u8) -> Option<Self> {
+        match pointer {
+            0x00 => Some(INA219Register::Configuration),
+            0x01 => Some(INA219Register::ShuntVoltage),
+            0x02 => Some(INA219Register::BusVoltage),
+            0x03 => Some(INA219Register::Power),
+            0x04 => Some(INA219Register::Current),
+            0x05 => Some(INA219Register::Calibration),
+            _ => None,
+        }
+    }
+
+    fn is_writable(self) -> bool {
+        matches!(
+            self,
+            INA219Register::Configuration | INA219Register::Calibration
+        )
+    }
+}
+
+/// Computes a register value on every read, e.g. from the wiper of a simulated potentiometer.
+type RegisterSource = Box<dyn Fn() -> u16 + Send>;
+
+/// Power-on value of the configuration register.
+const INA219_DEFAULT_CONFIGURATION: u16 = 0x399F;
+
+struct INA219State {
+    registers: HashMap<INA219Register, u16>,
+    stuck: HashMap<INA219Register, u16>,
+    sources: HashMap<INA219Register, RegisterSource>,
+    pointer: u8,
+    nack: bool,
+}
+
+/// Register map of an INA219 as seen over I2C: a one byte write selects the register pointer,
+/// a three byte write also stores a big-endian value, a read returns the selected register.
+#[derive(Clone)]
+pub struct SimulatedINA219 {
+    state: Arc<Mutex<INA219State>>,
+}
+
+impl Default for SimulatedINA219 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedINA219 {
+    pub fn new() -> Self {
+        let mut registers = HashMap::new();
+        registers.insert(INA219Register::Configuration, INA219_DEFAULT_CONFIGURATION);
+        SimulatedINA219 {
+            state: Arc::new(Mutex::new(INA219State {
+                registers,
+                stuck: HashMap::new(),
+                sources: HashMap::new(),
+                pointer: 0,
+                nack: false,
+            })),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, INA219State> {
+        self.state.lock().expect("Failed lock on simulated INA219")
+    }
+
+    pub fn register(&self, register: INA219Register) -> u16 {
+        let state = self.state();
+        if let Some(value) = state.stuck.get(&register) {
+            *value
+        } else if let Some(source) = state.sources.get(&register) {
+            source()
+        } else {
+            state.registers.get(&register).copied().unwrap_or(0)
+        }
+    }
+
+    pub fn set_register(&self, register: INA219Register, value: u16) {
+        let mut state = self.state();
+        state.sources.remove(&register);
+        state.registers.insert(register, value);
+    }
+
+    /// Lets `source` provide the register's value on every read until it is set again.
+    pub fn set_register_source(
+        &self,
+        register: INA219Register,
+        source: impl Fn() -> u16 + Send + 'static,
+    ) {
+        self.state().sources.insert(register, Box::new(source));
+    }
+
+    /// Raw shunt voltage register, LSB 10 µV.
+    pub fn set_shunt_voltage(&self, raw: i16) {
+        self.set_register(INA219Register::ShuntVoltage, raw as u16);
+    }
+
+    /// Bus voltage in mV, stored with the 4 mV LSB starting at bit 3.
+    pub fn set_bus_voltage_mv(&self, millivolts: u16) {
+        self.set_register(INA219Register::BusVoltage, (millivolts / 4) << 3);
+    }
+
+    /// Raw current register, scaled by `CURRENT_LSB` when read.
+    pub fn set_current(&self, raw: i16) {
+        self.set_register(INA219Register::Current, raw as u16);
+    }
+
+    /// Raw power register, scaled by `INA_219_POWER_FACTOR` when read.
+    pub fn set_power(&self, raw: i16) {
+        self.set_register(INA219Register::Power, raw as u16);
+    }
+
+    pub fn calibration(&self) -> u16 {
+        self.register(INA219Register::Calibration)
+    }
+
+    /// Makes the device refuse every transfer until cleared.
+    pub fn set_nack(&self, nack: bool) {
+        self.state().nack = nack;
+    }
+
+    /// Freezes a register at `value`, ignoring later writes and programmed values.
+    pub fn stick(&self, register: INA219Register, value: u16) {
+        self.state().stuck.insert(register, value);
+    }
+
+    pub fn unstick(&self, register: INA219Register) {
+        self.state().stuck.remove(&register);
+    }
+}
+
+impl SimulatedDevice for SimulatedINA219 {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Nack> {
+        let mut state = self.state();
+        if state.nack {
+            return Err(Nack);
+        }
+        match *bytes {
+            [pointer] => {
+                INA219Register::from_pointer(pointer).ok_or(Nack)?;
+                state.pointer = pointer;
+            }
+            [pointer, high, low] => {
+                let register = INA219Register::from_pointer(pointer).ok_or(Nack)?;
+                state.pointer = pointer;
+                if register.is_writable() {
+                    state
+                        .registers
+                        .insert(register, u16::from_be_bytes([high, low]));
+                }
+            }
+            _ => return Err(Nack),
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Nack> {
+        if self.state().nack {
+            return Err(Nack);
+        }
+        let register = INA219Register::from_pointer(self.state().pointer).ok_or(Nack)?;
+        let value = self.register(register).to_be_bytes();
+        for (byte, value_byte) in buffer.iter_mut().zip(value.iter().cycle()) {
+            *byte = *value_byte;
+        }
+        Ok(())
+    }
+}
+
+/// Power-on wiper position of the TPL potentiometer, mid-scale.
+const TPL_DEFAULT_WIPER_STEP: u8 = 0x40;
+
+struct TPLPotentiometerState {
+    wiper_step: u8,
+    stuck: bool,
+    nack: bool,
+    writes: Vec<u8>,
+}
+
+/// Single wiper register of a TPL potentiometer: a two byte write of command and step moves
+/// the wiper, a one byte command write followed by a read returns it.
+#[derive(Clone)]
+pub struct SimulatedTPLPotentiometer {
+    state: Arc<Mutex<TPLPotentiometerState>>,
+}
+
+impl Default for SimulatedTPLPotentiometer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedTPLPotentiometer {
+    pub fn new() -> Self {
+        SimulatedTPLPotentiometer {
+            state: Arc::new(Mutex::new(TPLPotentiometerState {
+                wiper_step: TPL_DEFAULT_WIPER_STEP,
+                stuck: false,
+                nack: false,
+                writes: Vec::new(),
+            })),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, TPLPotentiometerState> {
+        self.state
+            .lock()
+            .expect("Failed lock on simulated TPL potentiometer")
+    }
+
+    pub fn wiper_step(&self) -> u8 {
+        self.state().wiper_step
+    }
+
+    /// Every wiper step written over the bus, including ones ignored while stuck.
+    pub fn writes(&self) -> Vec<u8> {
+        self.state().writes.clone()
+    }
+
+    pub fn set_nack(&self, nack: bool) {
+        self.state().nack = nack;
+    }
+
+    /// Keeps the wiper where it is while still acknowledging writes.
+    pub fn set_stuck(&self, stuck: bool) {
+        self.state().stuck = stuck;
+    }
+}
+
+impl SimulatedDevice for SimulatedTPLPotentiometer {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Nack> {
+        let mut state = self.state();
+        if state.nack {
+            return Err(Nack);
+        }
+        match *bytes {
+            [0x00] => (),
+            [0x00, step] if step <= 0x7F => {
+                state.writes.push(step);
+                if !state.stuck {
+                    state.wiper_step = step;
+                }
+            }
+            _ => return Err(Nack),
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Nack> {
+        let state = self.state();
+        if state.nack {
+            return Err(Nack);
+        }
+        buffer.fill(state.wiper_step);
+        Ok(())
+    }
+}
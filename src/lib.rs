@@ -0,0 +1,45 @@
+//! Core of the SMACHA charging controller.
+//!
+//! The library builds for the host, so it can be checked with `cargo test` on a dev machine. The
+//! hardware, I2C, MQTT and storage modules are written against `embedded-hal` and the crate's
+//! own traits; only their ESP-IDF implementations (NVS storage, the ESP MQTT client and timer,
+//! the I2C driver setup) and the event service are compiled with the `esp` feature.
+
+pub mod async_timer;
+pub mod backoff;
+pub mod calibration;
+pub mod calibration_sweep;
+pub mod car;
+pub mod car_registry;
+pub mod charging_controller;
+pub mod charging_error;
+pub mod charging_mode;
+pub mod charging_regulator;
+pub mod charging_schedule;
+pub mod charging_session;
+pub mod command_response;
+pub mod context;
+pub mod controller_event;
+pub mod controller_status;
+pub mod departure_charging;
+pub mod energy_meter;
+pub mod handle_event_implementation;
+pub mod handler_functions;
+pub mod hardware_controller;
+pub mod i2c;
+#[cfg(test)]
+pub mod i2c_simulator;
+#[cfg(test)]
+pub mod mock_hardware;
+pub mod mqtt;
+pub mod network_config;
+pub mod network_status;
+pub mod solar_charging;
+pub mod storage;
+pub mod topic_router;
+pub mod tpl_potentiometer;
+pub mod trip;
+pub mod wall_clock;
+
+#[cfg(feature = "esp")]
+pub mod event_service;
@@ -1,54 +1,79 @@
-//! MQTT asynchronous client example which subscribes to an internet MQTT server and then sends
-//! and receives events in its own topic.
+//! Firmware of the SMACHA charging controller on the ESP32.
+//!
+//! Loads the network configuration, calibration, car registry, schedule and solar settings from
+//! NVS, joins the station network or falls back to an access point, and runs the MQTT session
+//! that routes commands to the library's handlers. A hardware loop regulates the charging output,
+//! books the delivered energy onto the car, publishes the INA219 stats and applies the charging
+//! modes.
 
 use core::pin::pin;
 use core::time::Duration;
+use std::cell::Cell;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-
-use car::Car;
-use charging_controller::ChargingController;
-use context::Context;
-use embassy_futures::select::{select, Either};
-
-mod car;
-mod charging_controller;
-mod context;
-mod event_service;
-mod handle_event_implementation;
-mod handler_functions;
-mod i2c;
-mod tpl_potentiometer;
+use std::time::Instant;
+
+use embassy_futures::select::{select, select4, Either4};
+use esp_idf_template::backoff::Backoff;
+use esp_idf_template::calibration::{ChargingCalibration, CALIBRATION_STORAGE_KEY};
+use esp_idf_template::calibration_sweep::calibrate;
+use esp_idf_template::car_registry::CarRegistry;
+use esp_idf_template::charging_controller::ChargingController;
+use esp_idf_template::charging_mode::{apply_charging_modes, ChargingMode};
+use esp_idf_template::charging_regulator::{regulate_charging, ChargingRegulator};
+use esp_idf_template::charging_schedule::{
+    load_charging_schedule, queue_charging_schedule, ScheduleState,
+};
+use esp_idf_template::context::Context;
+use esp_idf_template::controller_status::queue_status;
+use esp_idf_template::energy_meter::EnergyMeter;
+use esp_idf_template::event_service::handle_event;
+use esp_idf_template::handle_event_implementation::command_router;
+use esp_idf_template::hardware_controller::HardwareController;
+use esp_idf_template::i2c::{i2c_master_init, I2CDevices};
+use esp_idf_template::mqtt::{Outbox, AVAILABILITY_TOPIC, OFFLINE_PAYLOAD, ONLINE_PAYLOAD};
+use esp_idf_template::network_config::{
+    load_network_config, provision_config_token, NetworkConfig, MQTT_CLIENT_ID_PREFIX,
+};
+use esp_idf_template::network_status::{queue_network_status, NetworkStatus, WifiMode};
+use esp_idf_template::solar_charging::{load_solar_settings, SolarState};
+use esp_idf_template::storage::{load_json, NvsStorage, Storage};
+use esp_idf_template::topic_router::{device_topic_prefix, TopicRouter};
+use esp_idf_template::trip::{run_requested_trip, TripState};
+use esp_idf_template::wall_clock::unix_time_s;
 
 use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::gpio::{Gpio4, Output, PinDriver};
 use esp_idf_svc::hal::i2c::I2cDriver;
 use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::hal::peripheral::{Peripheral, PeripheralRef};
 use esp_idf_svc::hal::peripherals::Peripherals;
 use esp_idf_svc::mqtt::client::*;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sntp::EspSntp;
 use esp_idf_svc::sys::EspError;
-use esp_idf_svc::timer::{EspTimerService, Task};
+use esp_idf_svc::timer::{EspAsyncTimer, EspTimerService, Task};
 use esp_idf_svc::wifi::*;
 
-use anyhow::Result;
-use event_service::handle_event;
-use i2c::{i2c_master_init, I2CDevices};
+use anyhow::{anyhow, Result};
 use log::*;
+use shared_bus::I2cProxy;
 
-const CHANNEL: u8 = 11;
-
-const SSID: &str = "esp-wifi-access-point";
-const PASSWORD: &str = "thisismyhotspot1234";
+const MQTT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MQTT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often the MQTT session publishes the outbox.
+const MQTT_PUBLISH_INTERVAL: Duration = Duration::from_millis(200);
 
-const MQTT_URL: &str = "mqtt://192.168.71.2:1883";
-const MQTT_CLIENT_ID: &str = "esp-mqtt";
+/// Attempts to join the station network at boot before falling back to the access point.
+const STATION_CONNECT_ATTEMPTS: u32 = 4;
+const WIFI_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const WIFI_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often the Wi-Fi supervisor checks the station connection while it is up.
+const WIFI_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
-const TOPICS: [&str; 4] = [
-    "/charging-controller/start-charging",
-    "/charging-controller/change-charging-speed",
-    "/charging-controller/stop-charging",
-    "/charging-controller/start-trip",
-];
+type I2cBus = I2cProxy<'static, Mutex<I2cDriver<'static>>>;
+type TripMotorPin = PinDriver<'static, Gpio4, Output>;
 
 fn main() {
     esp_idf_svc::sys::link_patches();
@@ -68,96 +93,345 @@ fn main() {
 
     let shared_bus: &'static _ = shared_bus::new_std!(I2cDriver = i2c_master).unwrap();
 
+    let trip_motor_pin = PinDriver::output(peripherals.pins.gpio4).unwrap();
+
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+
     esp_idf_svc::hal::task::block_on(async {
-        let _wifi = wifi_create(peripherals.modem.into_ref())?;
+        let mut storage = NvsStorage::new(nvs.clone())?;
+        let mut network_config = load_network_config(&mut storage);
+
+        let (mut wifi, wifi_mode) =
+            wifi_create(peripherals.modem.into_ref(), nvs, &network_config)?;
         info!("Wifi created");
 
-        let mut i2c_devices = I2CDevices::new(&shared_bus.acquire_i2c()).unwrap();
+        // The hardware RNG is only truly random with the radio on.
+        provision_config_token(&mut storage, &mut network_config, || unsafe {
+            esp_idf_svc::sys::esp_random()
+        })?;
+
+        // Sets the system clock in the background and keeps it in sync while it lives.
+        let _sntp = EspSntp::new_default()?;
 
-        let (mut client, mut conn) = mqtt_create(MQTT_URL, MQTT_CLIENT_ID)?;
-        info!("MQTT client created");
+        let device_id = network_config.device_id(wifi.sta_netif().get_mac()?);
+        let topic_prefix = device_topic_prefix(&device_id);
+        info!("Device id is `{device_id}`, topic prefix `{topic_prefix}`");
 
-        run(&mut client, &mut conn, &timer_service, &mut i2c_devices).await?;
+        let context = initialize_context(storage, &topic_prefix)?;
+
+        let mut i2c_devices = I2CDevices::new(&shared_bus.acquire_i2c()).unwrap();
+        let hardware_controller = HardwareController::new(
+            i2c_devices.tpl_potentiometer.clone(),
+            trip_motor_pin,
+            context.calibration_rwlock.clone(),
+        );
+
+        let router = command_router(&topic_prefix);
+
+        run(
+            &network_config,
+            &device_id,
+            &router,
+            &timer_service,
+            &mut i2c_devices,
+            &hardware_controller,
+            &mut wifi,
+            wifi_mode,
+            context,
+        )
+        .await?;
         Ok::<(), anyhow::Error>(())
     })
     .unwrap();
 }
 
 async fn run(
-    client: &mut EspAsyncMqttClient,
-    connection: &mut EspAsyncMqttConnection,
+    network_config: &NetworkConfig,
+    device_id: &str,
+    router: &TopicRouter,
     timer_service: &EspTimerService<Task>,
-    i2c_devices: &mut I2CDevices<'_>,
+    i2c_devices: &mut I2CDevices<I2cBus>,
+    hardware_controller: &HardwareController<I2cBus, TripMotorPin>,
+    wifi: &mut EspWifi<'static>,
+    wifi_mode: WifiMode,
+    context: Context,
 ) -> Result<()> {
     info!("About to start the MQTT client");
 
     let mut first_timer = timer_service.timer_async()?;
     let mut second_timer = timer_service.timer_async()?;
+    let mut third_timer = timer_service.timer_async()?;
+    let mut fourth_timer = timer_service.timer_async()?;
 
-    let context = initialize_context()?;
+    let mqtt_client_id = format!("{MQTT_CLIENT_ID_PREFIX}{device_id}");
+    let availability_topic = context.outbox.topic(AVAILABILITY_TOPIC);
 
-    let res = select(
-        // Need to immediately start pumping the connection for messages, or else subscribe() and publish() below will not work
-        // Note that when using the alternative structure and the alternative constructor - `EspMqttClient::new_cb` - you don't need to
-        // spawn a new thread, as the messages will be pumped with a backpressure into the callback you provide.
-        // Yet, you still need to efficiently process each message in the callback without blocking for too long.
-        //
-        // Note also that if you go to http://tools.emqx.io/ and then connect and send a message to topic
-        // "esp-mqtt-demo", the client configured here should receive it.
-        pin!(async move {
-            info!("MQTT Listening for messages");
+    let hardware_context = context.clone();
+    let trip_context = context.clone();
+    let wifi_context = context.clone();
 
-            while let Ok(event) = connection.next().await {
-                match handle_event(&mut first_timer, event.payload(), context.clone()).await {
-                    Ok(_) => (),
-                    Err(error) => info!("{error}"),
+    let res = select4(
+        pin!(async move {
+            // A session ends when the broker connection drops, the next one starts with a fresh
+            // client after a backoff.
+            let mut backoff = Backoff::new(MQTT_INITIAL_BACKOFF, MQTT_MAX_BACKOFF);
+            loop {
+                match mqtt_create(
+                    &network_config.mqtt_url,
+                    &mqtt_client_id,
+                    &availability_topic,
+                ) {
+                    Ok((mut client, mut connection)) => {
+                        info!("MQTT client created");
+                        mqtt_session(
+                            &mut client,
+                            &mut connection,
+                            &mut first_timer,
+                            &mut backoff,
+                            router,
+                            &availability_topic,
+                            &context,
+                        )
+                        .await;
+                    }
+                    Err(error) => error!("Failed to create the MQTT client: {error}"),
                 }
-            }
-
-            info!("Connection closed");
 
-            Ok(())
+                let delay = backoff.next_delay();
+                info!("Reconnecting to the MQTT broker in {delay:?}");
+                first_timer.after(delay).await?;
+            }
         }),
         pin!(async move {
             // Using `pin!` is optional, but it optimizes the memory size of the Futures
-            for topic in TOPICS {
-                while let Err(e) = client.subscribe(topic, QoS::AtMostOnce).await {
-                    error!("Failed to subscribe to topic \"{topic}\": {e}, retrying...");
-
-                    // Re-try in 0.5s
-                    second_timer.after(Duration::from_millis(500)).await?;
+            let mut outbox = hardware_context.outbox.clone();
+            let mut charging_regulator = ChargingRegulator::new();
+            let mut energy_meter = EnergyMeter::new();
+            let mut last_tick = Instant::now();
+            loop {
+                if hardware_context
+                    .calibration_requested
+                    .load(Ordering::SeqCst)
+                {
+                    if let Err(error) = calibrate(
+                        &hardware_context,
+                        hardware_controller,
+                        &mut i2c_devices.power_ina_219,
+                        &mut second_timer,
+                        &mut outbox,
+                    )
+                    .await
+                    {
+                        error!("Calibration failed: {error}");
+                    }
+                    // The sweep left the wiper at its lowest current step.
+                    charging_regulator.reset();
+                    hardware_context
+                        .calibration_requested
+                        .store(false, Ordering::SeqCst);
+                }
 
-                    continue;
+                let measured_power_w = match regulate_charging(
+                    &mut charging_regulator,
+                    &hardware_context,
+                    hardware_controller,
+                    &mut i2c_devices.power_ina_219,
+                    &mut outbox,
+                )
+                .await
+                {
+                    Ok(regulator_stats) => {
+                        regulator_stats.map(|regulator_stats| regulator_stats.measured_w)
+                    }
+                    Err(error) => {
+                        error!("Charging regulation failed: {error}");
+                        None
+                    }
+                };
+
+                let now = Instant::now();
+                let elapsed = now - last_tick;
+                if let Err(error) = energy_meter
+                    .integrate(&hardware_context, measured_power_w, elapsed, &mut outbox)
+                    .await
+                {
+                    error!("Energy integration failed: {error}");
+                }
+                last_tick = now;
+
+                // Without a reading the sun counts as gone.
+                let solar_power_w = match i2c_devices.publish_stats(&mut outbox).await {
+                    Ok(solar_stats) => solar_stats.power_w(),
+                    Err(error) => {
+                        error!("Failed to read INA219 stats: {error}");
+                        0.0
+                    }
+                };
+
+                if let Err(error) = apply_charging_modes(
+                    &hardware_context,
+                    unix_time_s(),
+                    solar_power_w,
+                    elapsed,
+                    &mut outbox,
+                )
+                .await
+                {
+                    error!("Automatic charging failed: {error}");
                 }
-            }
 
-            // Just to give a chance of our connection to get even the first published message
-            second_timer.after(Duration::from_millis(500)).await?;
+                second_timer.after(Duration::from_millis(1000)).await?;
+            }
+        }),
+        pin!(async move {
+            let mut outbox = trip_context.outbox.clone();
+            loop {
+                if let Err(error) = run_requested_trip(
+                    &trip_context,
+                    hardware_controller,
+                    &mut third_timer,
+                    &mut outbox,
+                )
+                .await
+                {
+                    error!("Trip failed: {error}");
+                }
 
+                third_timer.after(Duration::from_millis(500)).await?;
+            }
+        }),
+        pin!(async move {
+            // Keeps the station connected, the driver does not reconnect on its own.
+            let mut backoff = Backoff::new(WIFI_INITIAL_BACKOFF, WIFI_MAX_BACKOFF);
+            let mut network_status = current_network_status(wifi, wifi_mode);
+            queue_network_status(&wifi_context.outbox, &network_status);
             loop {
-                i2c_devices
-                    .write_mqtt_messages(&mut second_timer, client)
-                    .await
-                    .unwrap();
+                let delay =
+                    if wifi_mode == WifiMode::AccessPoint || wifi.is_connected().unwrap_or(false) {
+                        backoff.reset();
+                        WIFI_CHECK_INTERVAL
+                    } else {
+                        if let Err(error) = wifi.connect() {
+                            warn!("Failed to reconnect to the station network: {error}");
+                        }
+                        backoff.next_delay()
+                    };
+
+                let new_network_status = current_network_status(wifi, wifi_mode);
+                if new_network_status != network_status {
+                    info!("Network status changed: {:?}", new_network_status);
+                    queue_network_status(&wifi_context.outbox, &new_network_status);
+                    network_status = new_network_status;
+                }
+
+                fourth_timer.after(delay).await?;
             }
         }),
     )
     .await;
 
     match res {
-        Either::First(res) => res,
-        Either::Second(res) => res,
+        Either4::First(res) => res,
+        Either4::Second(res) => res,
+        Either4::Third(res) => res,
+        Either4::Fourth(res) => res,
     }
 }
 
+/// Pumps the connection and publishes the outbox until the broker connection drops. Announces
+/// the device as online and subscribes to the command topics on every `Connected` event, so a
+/// reconnect restores them.
+async fn mqtt_session(
+    client: &mut EspAsyncMqttClient,
+    connection: &mut EspAsyncMqttConnection,
+    timer: &mut EspAsyncTimer,
+    backoff: &mut Backoff,
+    router: &TopicRouter,
+    availability_topic: &str,
+    context: &Context,
+) {
+    let connected = Cell::new(false);
+    let subscribe_requested = Cell::new(false);
+
+    select(
+        // Need to immediately start pumping the connection for messages, or else subscribe() and
+        // publish() below will not work.
+        pin!(async {
+            info!("MQTT Listening for messages");
+
+            while let Ok(event) = connection.next().await {
+                let payload = event.payload();
+                match payload {
+                    EventPayload::Connected(_) => {
+                        info!("MQTT connected");
+                        backoff.reset();
+                        connected.set(true);
+                        subscribe_requested.set(true);
+                    }
+                    EventPayload::Disconnected => {
+                        warn!("MQTT disconnected");
+                        return;
+                    }
+                    EventPayload::Received { .. } => {
+                        if let Err(error) = handle_event(payload, router, context.clone()) {
+                            info!("{error}");
+                        }
+                    }
+                    EventPayload::Error(error) => warn!("MQTT error: {error}"),
+                    _ => (),
+                }
+            }
+
+            info!("Connection closed");
+        }),
+        pin!(async {
+            loop {
+                if connected.get() {
+                    if subscribe_requested.take() {
+                        if let Err(error) = client
+                            .publish(availability_topic, QoS::AtLeastOnce, true, ONLINE_PAYLOAD)
+                            .await
+                        {
+                            error!("Failed to publish the birth message: {error}");
+                        }
+                        for (topic, qos) in router.subscriptions() {
+                            if let Err(error) = client.subscribe(&topic, qos.into()).await {
+                                error!("Failed to subscribe to topic \"{topic}\": {error}, retrying...");
+                                subscribe_requested.set(true);
+                                break;
+                            }
+                        }
+                    }
+                    if let Err(error) = context.outbox.flush(client).await {
+                        error!("Failed to publish queued messages: {error}");
+                    }
+                }
+
+                if let Err(error) = timer.after(MQTT_PUBLISH_INTERVAL).await {
+                    error!("MQTT timer failed: {error}");
+                    return;
+                }
+            }
+        }),
+    )
+    .await;
+}
+
 fn mqtt_create(
     url: &str,
     client_id: &str,
+    availability_topic: &str,
 ) -> Result<(EspAsyncMqttClient, EspAsyncMqttConnection), EspError> {
     let (mqtt_client, mqtt_conn) = EspAsyncMqttClient::new(
         url,
         &MqttClientConfiguration {
             client_id: Some(client_id),
+            lwt: Some(LwtConfiguration {
+                topic: availability_topic,
+                payload: OFFLINE_PAYLOAD,
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
             ..Default::default()
         },
     )?;
@@ -165,41 +439,152 @@ fn mqtt_create(
     Ok((mqtt_client, mqtt_conn))
 }
 
-fn wifi_create(modem: PeripheralRef<'static, Modem>) -> Result<EspWifi<'static>, EspError> {
+fn wifi_create(
+    modem: PeripheralRef<'static, Modem>,
+    nvs: EspDefaultNvsPartition,
+    network_config: &NetworkConfig,
+) -> Result<(EspWifi<'static>, WifiMode)> {
     let sys_loop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
 
     let mut esp_wifi = EspWifi::new(modem, sys_loop.clone(), Some(nvs))?;
     let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sys_loop)?;
 
-    let wifi_configuration = Configuration::AccessPoint(AccessPointConfiguration {
-        ssid: SSID.try_into().unwrap(),
+    let access_point_configuration = AccessPointConfiguration {
+        ssid: network_config
+            .wifi_ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow!("Wi-Fi SSID is too long"))?,
         ssid_hidden: false,
         auth_method: AuthMethod::WPA2Personal,
-        password: PASSWORD.try_into().unwrap(),
-        channel: CHANNEL,
+        password: network_config
+            .wifi_password
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow!("Wi-Fi password is too long"))?,
+        channel: network_config.wifi_channel,
         ..Default::default()
-    });
-    wifi.set_configuration(&wifi_configuration)?;
-    wifi.start()?;
-    wifi.wait_netif_up()?;
+    };
 
+    let wifi_mode = if network_config.station_ssid.is_empty() {
+        wifi.set_configuration(&Configuration::AccessPoint(access_point_configuration))?;
+        wifi.start()?;
+        wifi.wait_netif_up()?;
+        WifiMode::AccessPoint
+    } else {
+        let client_configuration = ClientConfiguration {
+            ssid: network_config
+                .station_ssid
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow!("Station SSID is too long"))?,
+            auth_method: if network_config.station_password.is_empty() {
+                AuthMethod::None
+            } else {
+                AuthMethod::WPA2Personal
+            },
+            password: network_config
+                .station_password
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow!("Station password is too long"))?,
+            ..Default::default()
+        };
+        wifi.set_configuration(&Configuration::Client(client_configuration.clone()))?;
+        wifi.start()?;
+        if connect_station(&mut wifi, &network_config.station_ssid) {
+            WifiMode::Station
+        } else {
+            warn!(
+                "Could not join `{}`, falling back to the access point `{}`",
+                network_config.station_ssid, network_config.wifi_ssid
+            );
+            wifi.stop()?;
+            wifi.set_configuration(&Configuration::Mixed(
+                client_configuration,
+                access_point_configuration,
+            ))?;
+            wifi.start()?;
+            WifiMode::Mixed
+        }
+    };
+
+    let network_status = current_network_status(&esp_wifi, wifi_mode);
     info!(
-        "Created Wi-Fi with WIFI_SSID `{}` and WIFI_PASS `{}`",
-        SSID, PASSWORD
+        "Created Wi-Fi in {:?} mode with IP {:?}",
+        network_status.wifi_mode, network_status.ip
     );
 
-    Ok(esp_wifi)
+    Ok((esp_wifi, wifi_mode))
+}
+
+/// Tries to join the station network, backing off between attempts.
+fn connect_station(wifi: &mut BlockingWifi<&mut EspWifi<'static>>, ssid: &str) -> bool {
+    let mut backoff = Backoff::new(WIFI_INITIAL_BACKOFF, WIFI_MAX_BACKOFF);
+    for attempt in 1..=STATION_CONNECT_ATTEMPTS {
+        match wifi.connect().and_then(|()| wifi.wait_netif_up()) {
+            Ok(()) => return true,
+            Err(error) => {
+                warn!("Failed to join `{ssid}` (attempt {attempt}/{STATION_CONNECT_ATTEMPTS}): {error}");
+                if attempt < STATION_CONNECT_ATTEMPTS {
+                    std::thread::sleep(backoff.next_delay());
+                }
+            }
+        }
+    }
+    false
 }
 
-fn initialize_context() -> Result<Context> {
+/// Reports the station IP while the station is connected, otherwise the access point IP.
+fn current_network_status(esp_wifi: &EspWifi<'static>, wifi_mode: WifiMode) -> NetworkStatus {
+    let station_connected =
+        wifi_mode != WifiMode::AccessPoint && esp_wifi.is_connected().unwrap_or(false);
+    let netif = match wifi_mode {
+        _ if station_connected => Some(esp_wifi.sta_netif()),
+        WifiMode::Station => None,
+        WifiMode::AccessPoint | WifiMode::Mixed => Some(esp_wifi.ap_netif()),
+    };
+    let ip = netif
+        .and_then(|netif| netif.get_ip_info().ok())
+        .map(|ip_info| Ipv4Addr::from(ip_info.ip.octets()));
+    NetworkStatus {
+        wifi_mode,
+        station_connected,
+        ip,
+    }
+}
+
+fn initialize_context(mut storage: impl Storage + 'static, topic_prefix: &str) -> Result<Context> {
+    let calibration: ChargingCalibration = load_json(&mut storage, CALIBRATION_STORAGE_KEY)
+        .unwrap_or_else(|error| {
+            warn!("Stored charging calibration is unreadable, using the default: {error}");
+            None
+        })
+        .unwrap_or_default();
+    let car_registry = CarRegistry::load(&mut storage).unwrap_or_else(|error| {
+        warn!("Stored cars are unreadable, starting without cars: {error}");
+        CarRegistry::new()
+    });
+    let charging_schedule = load_charging_schedule(&mut storage);
+    let solar_settings = load_solar_settings(&mut storage);
     let context = Context {
         charging_controller_mutex: Arc::new(Mutex::new(ChargingController::new())),
-        car_rwlock: Arc::new(RwLock::new(Car::new(3700, 0, 100)?)),
-    };
-    {
-        let mut charging_controller = context.charging_controller_mutex.lock().unwrap();
-        charging_controller.connect_car(context.car_rwlock.clone())?;
+        car_registry_rwlock: Arc::new(RwLock::new(car_registry)),
+        calibration_rwlock: Arc::new(RwLock::new(calibration)),
+        storage_mutex: Arc::new(Mutex::new(storage)),
+        calibration_requested: Arc::new(AtomicBool::new(false)),
+        trip_state_mutex: Arc::new(Mutex::new(TripState::Idle)),
+        outbox: Outbox::with_topic_prefix(topic_prefix),
+        charging_schedule_rwlock: Arc::new(RwLock::new(charging_schedule.clone())),
+        schedule_state_mutex: Arc::new(Mutex::new(ScheduleState::new())),
+        departure_plan_mutex: Arc::new(Mutex::new(None)),
+        solar_settings_rwlock: Arc::new(RwLock::new(solar_settings)),
+        solar_state_mutex: Arc::new(Mutex::new(SolarState::new())),
+        charging_mode_mutex: Arc::new(Mutex::new(ChargingMode::Manual)),
+        charging_session_mutex: Arc::new(Mutex::new(None)),
     };
+    queue_charging_schedule(&context.outbox, &charging_schedule);
+    // Cars are plugged in through the connect-car command.
+    queue_status(&context, &context.charging_controller_mutex.lock().unwrap());
     Ok(context)
 }
@@ -0,0 +1,144 @@
+//! Recording stand-ins for the board's actuators, so trip timing and pin sequencing can be
+//! checked on the host. Time is simulated: waiting on a [`MockTimer`] advances a [`MockClock`]
+//! instantly, and every pin change is stamped with the clock's current value.
+
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::async_timer::AsyncTimer;
+
+#[derive(Clone, Default)]
+pub struct MockClock {
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("Failed lock on mock clock")
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().expect("Failed lock on mock clock") += duration;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PinLevel {
+    High,
+    Low,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PinChange {
+    pub at: Duration,
+    pub level: PinLevel,
+}
+
+/// Output pin recording every level change. Clones share the same record, so a clone kept by
+/// the test sees what the controller owning the pin did.
+#[derive(Clone)]
+pub struct RecordingOutputPin {
+    clock: MockClock,
+    changes: Arc<Mutex<Vec<PinChange>>>,
+}
+
+impl RecordingOutputPin {
+    pub fn new(clock: MockClock) -> Self {
+        RecordingOutputPin {
+            clock,
+            changes: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn changes(&self) -> Vec<PinChange> {
+        self.changes
+            .lock()
+            .expect("Failed lock on pin changes")
+            .clone()
+    }
+
+    pub fn level(&self) -> PinLevel {
+        self.changes()
+            .last()
+            .map_or(PinLevel::Low, |change| change.level)
+    }
+
+    fn record(&mut self, level: PinLevel) {
+        self.changes
+            .lock()
+            .expect("Failed lock on pin changes")
+            .push(PinChange {
+                at: self.clock.elapsed(),
+                level,
+            });
+    }
+}
+
+impl OutputPin for RecordingOutputPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.record(PinLevel::Low);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.record(PinLevel::High);
+        Ok(())
+    }
+}
+
+/// Timer that returns immediately and advances the shared [`MockClock`] instead. A failing
+/// timer returns an error without advancing the clock.
+#[derive(Clone)]
+pub struct MockTimer {
+    clock: MockClock,
+    waits: Arc<Mutex<Vec<Duration>>>,
+    failing: Arc<Mutex<bool>>,
+}
+
+impl MockTimer {
+    pub fn new(clock: MockClock) -> Self {
+        MockTimer {
+            clock,
+            waits: Arc::new(Mutex::new(Vec::new())),
+            failing: Arc::new(Mutex::new(false)),
+        }
+    }
+
+    /// Makes every wait fail until cleared.
+    pub fn set_failing(&self, failing: bool) {
+        *self.failing.lock().expect("Failed lock on timer failing") = failing;
+    }
+
+    pub fn waits(&self) -> Vec<Duration> {
+        self.waits
+            .lock()
+            .expect("Failed lock on timer waits")
+            .clone()
+    }
+}
+
+impl AsyncTimer for MockTimer {
+    async fn after(&mut self, duration: Duration) -> Result<()> {
+        self.waits
+            .lock()
+            .expect("Failed lock on timer waits")
+            .push(duration);
+        if *self.failing.lock().expect("Failed lock on timer failing") {
+            Err(anyhow!("Mock timer failed"))?
+        }
+        self.clock.advance(duration);
+        Ok(())
+    }
+}
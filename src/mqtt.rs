@@ -0,0 +1,194 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use crate::topic_router::DEFAULT_TOPIC_PREFIX;
+
+/// Topic of the retained availability message, "online" once connected and "offline" as the
+/// last will.
+pub const AVAILABILITY_TOPIC: &str = "availability";
+pub const ONLINE_PAYLOAD: &[u8] = b"online";
+pub const OFFLINE_PAYLOAD: &[u8] = b"offline";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+/// Outgoing side of the MQTT client, so publishing code runs against `EspAsyncMqttClient` on the
+/// device and against [`RecordingPublisher`] on the host.
+#[allow(async_fn_in_trait)]
+pub trait MqttPublisher {
+    async fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: &[u8]) -> Result<()>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublishedMessage {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+}
+
+/// Publisher keeping every message instead of sending it.
+#[derive(Default)]
+pub struct RecordingPublisher {
+    pub messages: Vec<PublishedMessage>,
+}
+
+impl MqttPublisher for RecordingPublisher {
+    async fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: &[u8]) -> Result<()> {
+        self.messages.push(PublishedMessage {
+            topic: topic.to_string(),
+            qos,
+            retain,
+            payload: payload.to_vec(),
+        });
+        Ok(())
+    }
+}
+
+/// Messages the outbox holds at most. Once full, the oldest `AtMostOnce` message is dropped to
+/// make room, or the oldest message if there is none.
+pub const OUTBOX_CAPACITY: usize = 64;
+
+/// Queue between the code producing messages and the MQTT session publishing them, so
+/// producers keep going while the broker is unreachable. Clones share the queue.
+///
+/// Topics starting with `/` are published as they are, all others below the device's topic
+/// prefix. Topics the device received a message on are queued with [`Outbox::push_absolute`].
+#[derive(Clone)]
+pub struct Outbox {
+    topic_prefix: String,
+    messages: Arc<Mutex<VecDeque<PublishedMessage>>>,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::with_topic_prefix(DEFAULT_TOPIC_PREFIX)
+    }
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_topic_prefix(topic_prefix: &str) -> Self {
+        Outbox {
+            topic_prefix: topic_prefix.trim_end_matches('/').to_string(),
+            messages: Arc::default(),
+        }
+    }
+
+    pub fn topic_prefix(&self) -> &str {
+        &self.topic_prefix
+    }
+
+    /// Full topic of `topic`, see [`Outbox`].
+    pub fn topic(&self, topic: &str) -> String {
+        if topic.starts_with('/') {
+            topic.to_string()
+        } else {
+            format!("{}/{topic}", self.topic_prefix)
+        }
+    }
+
+    pub fn push(&self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) {
+        self.push_absolute(&self.topic(topic), qos, retain, payload);
+    }
+
+    /// Queues a message on `topic` exactly as given, without the device's topic prefix.
+    pub fn push_absolute(&self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) {
+        let mut messages = self.messages.lock().expect("Failed lock on outbox");
+        if messages.len() >= OUTBOX_CAPACITY {
+            let dropped = messages
+                .iter()
+                .position(|message| message.qos == QoS::AtMostOnce)
+                .unwrap_or(0);
+            messages.remove(dropped);
+        }
+        messages.push_back(PublishedMessage {
+            topic: topic.to_string(),
+            qos,
+            retain,
+            payload,
+        });
+    }
+
+    pub fn drain(&self) -> Vec<PublishedMessage> {
+        self.messages
+            .lock()
+            .expect("Failed lock on outbox")
+            .drain(..)
+            .collect()
+    }
+
+    /// Publishes the queued messages in order. A message that fails stays queued with the ones
+    /// behind it.
+    pub async fn flush(&self, mqtt_client: &mut impl MqttPublisher) -> Result<()> {
+        loop {
+            let Some(message) = self
+                .messages
+                .lock()
+                .expect("Failed lock on outbox")
+                .pop_front()
+            else {
+                return Ok(());
+            };
+            if let Err(error) = mqtt_client
+                .publish(
+                    &message.topic,
+                    message.qos,
+                    message.retain,
+                    &message.payload,
+                )
+                .await
+            {
+                self.messages
+                    .lock()
+                    .expect("Failed lock on outbox")
+                    .push_front(message);
+                return Err(error);
+            }
+        }
+    }
+}
+
+impl MqttPublisher for Outbox {
+    async fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: &[u8]) -> Result<()> {
+        self.push(topic, qos, retain, payload.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "esp")]
+impl From<QoS> for esp_idf_svc::mqtt::client::QoS {
+    fn from(qos: QoS) -> Self {
+        match qos {
+            QoS::AtMostOnce => esp_idf_svc::mqtt::client::QoS::AtMostOnce,
+            QoS::AtLeastOnce => esp_idf_svc::mqtt::client::QoS::AtLeastOnce,
+            QoS::ExactlyOnce => esp_idf_svc::mqtt::client::QoS::ExactlyOnce,
+        }
+    }
+}
+
+#[cfg(feature = "esp")]
+impl MqttPublisher for esp_idf_svc::mqtt::client::EspAsyncMqttClient {
+    async fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: &[u8]) -> Result<()> {
+        esp_idf_svc::mqtt::client::EspAsyncMqttClient::publish(
+            self,
+            topic,
+            qos.into(),
+            retain,
+            payload,
+        )
+        .await?;
+        Ok(())
+    }
+}
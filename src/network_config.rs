@@ -0,0 +1,410 @@
+use std::fmt::{self, Display};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command_response::CommandError,
+    storage::{load_json, store_json, Storage},
+};
+
+/// Storage key of the network configuration.
+pub const NETWORK_CONFIG_STORAGE_KEY: &str = "network_config";
+
+/// Layout version of [`NetworkConfig`]. Bump it when a field changes meaning; added fields only
+/// need a default.
+///
+/// Version 2 replaced the MQTT client id with the device id.
+pub const NETWORK_CONFIG_VERSION: u32 = 2;
+
+pub const DEFAULT_WIFI_CHANNEL: u8 = 11;
+pub const DEFAULT_WIFI_SSID: &str = "esp-wifi-access-point";
+pub const DEFAULT_WIFI_PASSWORD: &str = "thisismyhotspot1234";
+pub const DEFAULT_MQTT_URL: &str = "mqtt://192.168.71.2:1883";
+/// Prefix of the MQTT client id, followed by the device id.
+pub const MQTT_CLIENT_ID_PREFIX: &str = "smacha-";
+/// Token every device used to ship with. A stored config still carrying it gets a token of its
+/// own like a fresh device.
+const LEGACY_CONFIG_TOKEN: &str = "smacha-config";
+/// Random bytes of a generated config token.
+const CONFIG_TOKEN_BYTES: usize = 16;
+
+const MAX_SSID_LENGTH: usize = 32;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_PASSWORD_LENGTH: usize = 64;
+const MAX_WIFI_CHANNEL: u8 = 13;
+const MAX_DEVICE_ID_LENGTH: usize = 32;
+
+/// Settings read once at boot to bring up Wi-Fi and MQTT. Changes are stored and only take
+/// effect after a restart. The device joins the station network if one is configured and opens
+/// its own access point with `wifi_ssid` otherwise, or when the station network is unreachable.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub version: u32,
+    pub wifi_ssid: String,
+    pub wifi_password: String,
+    pub wifi_channel: u8,
+    /// Network to join as a client, empty for access point only.
+    pub station_ssid: String,
+    /// Empty for an open network.
+    pub station_password: String,
+    pub mqtt_url: String,
+    /// Names the device in its MQTT client id and topics, empty to derive it from the MAC.
+    pub device_id: String,
+    /// Secret the config command is authenticated with. Generated per device at first boot,
+    /// empty until then, which refuses every token.
+    pub config_token: String,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            version: NETWORK_CONFIG_VERSION,
+            wifi_ssid: DEFAULT_WIFI_SSID.to_string(),
+            wifi_password: DEFAULT_WIFI_PASSWORD.to_string(),
+            wifi_channel: DEFAULT_WIFI_CHANNEL,
+            station_ssid: String::new(),
+            station_password: String::new(),
+            mqtt_url: DEFAULT_MQTT_URL.to_string(),
+            device_id: String::new(),
+            config_token: String::new(),
+        }
+    }
+}
+
+/// Fields of the config command. Missing fields keep their stored value.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NetworkConfigUpdate {
+    pub wifi_ssid: Option<String>,
+    pub wifi_password: Option<String>,
+    pub wifi_channel: Option<u8>,
+    pub station_ssid: Option<String>,
+    pub station_password: Option<String>,
+    pub mqtt_url: Option<String>,
+    pub device_id: Option<String>,
+    pub config_token: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "error", rename_all = "kebab-case")]
+pub enum NetworkConfigError {
+    Unauthorized,
+    UnsupportedVersion { version: u32 },
+    InvalidWifiSsid,
+    InvalidWifiPassword,
+    InvalidWifiChannel { channel: u8 },
+    InvalidMqttUrl,
+    InvalidConfigToken,
+    InvalidStationSsid,
+    InvalidStationPassword,
+    InvalidDeviceId,
+}
+
+impl CommandError for NetworkConfigError {
+    fn code(&self) -> u16 {
+        match self {
+            NetworkConfigError::Unauthorized => 600,
+            NetworkConfigError::UnsupportedVersion { .. } => 601,
+            NetworkConfigError::InvalidWifiSsid => 602,
+            NetworkConfigError::InvalidWifiPassword => 603,
+            NetworkConfigError::InvalidWifiChannel { .. } => 604,
+            NetworkConfigError::InvalidMqttUrl => 605,
+            NetworkConfigError::InvalidConfigToken => 607,
+            NetworkConfigError::InvalidStationSsid => 608,
+            NetworkConfigError::InvalidStationPassword => 609,
+            NetworkConfigError::InvalidDeviceId => 610,
+        }
+    }
+}
+
+impl Display for NetworkConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkConfigError::Unauthorized => write!(f, "Config token does not match"),
+            NetworkConfigError::UnsupportedVersion { version } => write!(
+                f,
+                "Network config version {version} is newer than the supported version {NETWORK_CONFIG_VERSION}"
+            ),
+            NetworkConfigError::InvalidWifiSsid => write!(
+                f,
+                "Wi-Fi SSID must have between 1 and {MAX_SSID_LENGTH} bytes"
+            ),
+            NetworkConfigError::InvalidWifiPassword => write!(
+                f,
+                "Wi-Fi password must have between {MIN_PASSWORD_LENGTH} and {MAX_PASSWORD_LENGTH} bytes"
+            ),
+            NetworkConfigError::InvalidWifiChannel { channel } => write!(
+                f,
+                "Wi-Fi channel {channel} is not between 1 and {MAX_WIFI_CHANNEL}"
+            ),
+            NetworkConfigError::InvalidMqttUrl => {
+                write!(f, "MQTT URL must start with mqtt:// or mqtts://")
+            }
+            NetworkConfigError::InvalidConfigToken => write!(f, "Config token must not be empty"),
+            NetworkConfigError::InvalidStationSsid => write!(
+                f,
+                "Station SSID must have at most {MAX_SSID_LENGTH} bytes"
+            ),
+            NetworkConfigError::InvalidStationPassword => write!(
+                f,
+                "Station password must be empty or have between {MIN_PASSWORD_LENGTH} and {MAX_PASSWORD_LENGTH} bytes"
+            ),
+            NetworkConfigError::InvalidDeviceId => write!(
+                f,
+                "Device id must have at most {MAX_DEVICE_ID_LENGTH} letters, digits, '-' or '_'"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NetworkConfigError {}
+
+impl NetworkConfig {
+    pub fn validate(&self) -> Result<(), NetworkConfigError> {
+        if self.version > NETWORK_CONFIG_VERSION {
+            Err(NetworkConfigError::UnsupportedVersion {
+                version: self.version,
+            })
+        } else if self.wifi_ssid.is_empty() || self.wifi_ssid.len() > MAX_SSID_LENGTH {
+            Err(NetworkConfigError::InvalidWifiSsid)
+        } else if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&self.wifi_password.len()) {
+            Err(NetworkConfigError::InvalidWifiPassword)
+        } else if !(1..=MAX_WIFI_CHANNEL).contains(&self.wifi_channel) {
+            Err(NetworkConfigError::InvalidWifiChannel {
+                channel: self.wifi_channel,
+            })
+        } else if !(self.mqtt_url.starts_with("mqtt://") || self.mqtt_url.starts_with("mqtts://")) {
+            Err(NetworkConfigError::InvalidMqttUrl)
+        } else if self.station_ssid.len() > MAX_SSID_LENGTH {
+            Err(NetworkConfigError::InvalidStationSsid)
+        } else if !(self.station_password.is_empty()
+            || (MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&self.station_password.len()))
+        {
+            Err(NetworkConfigError::InvalidStationPassword)
+        } else if self.device_id.len() > MAX_DEVICE_ID_LENGTH
+            || !self
+                .device_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            Err(NetworkConfigError::InvalidDeviceId)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Compares in constant time, so the token cannot be guessed byte by byte from the timing.
+    /// Nothing is authorized before the device has a token.
+    pub fn is_authorized(&self, token: &str) -> bool {
+        let expected = self.config_token.as_bytes();
+        let given = token.as_bytes();
+        !expected.is_empty()
+            && expected.len() == given.len()
+            && expected
+                .iter()
+                .zip(given)
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+
+    /// The configured device id, or the one derived from `mac` if none is configured.
+    pub fn device_id(&self, mac: [u8; 6]) -> String {
+        if self.device_id.is_empty() {
+            device_id_from_mac(mac)
+        } else {
+            self.device_id.clone()
+        }
+    }
+
+    /// Copy of the config with the given fields replaced, validated.
+    pub fn updated(&self, update: NetworkConfigUpdate) -> Result<Self, NetworkConfigError> {
+        if update.config_token.as_ref().is_some_and(String::is_empty) {
+            Err(NetworkConfigError::InvalidConfigToken)?
+        }
+        let config = NetworkConfig {
+            version: NETWORK_CONFIG_VERSION,
+            wifi_ssid: update.wifi_ssid.unwrap_or_else(|| self.wifi_ssid.clone()),
+            wifi_password: update
+                .wifi_password
+                .unwrap_or_else(|| self.wifi_password.clone()),
+            wifi_channel: update.wifi_channel.unwrap_or(self.wifi_channel),
+            station_ssid: update
+                .station_ssid
+                .unwrap_or_else(|| self.station_ssid.clone()),
+            station_password: update
+                .station_password
+                .unwrap_or_else(|| self.station_password.clone()),
+            mqtt_url: update.mqtt_url.unwrap_or_else(|| self.mqtt_url.clone()),
+            device_id: update.device_id.unwrap_or_else(|| self.device_id.clone()),
+            config_token: update
+                .config_token
+                .unwrap_or_else(|| self.config_token.clone()),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Lowercase hex of the MAC address, e.g. `a0b1c2d3e4f5`.
+pub fn device_id_from_mac(mac: [u8; 6]) -> String {
+    mac.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Loads the stored network config. Falls back to the defaults if none is stored or the stored
+/// one is unusable, so a bad config cannot lock the device out of the network for good.
+pub fn load_network_config(storage: &mut dyn Storage) -> NetworkConfig {
+    let config = match load_json::<NetworkConfig>(storage, NETWORK_CONFIG_STORAGE_KEY) {
+        Ok(config) => config.unwrap_or_default(),
+        Err(error) => {
+            warn!("Stored network config is unreadable, using the default: {error}");
+            NetworkConfig::default()
+        }
+    };
+    match config.validate() {
+        Ok(()) => config,
+        Err(error) => {
+            warn!("Stored network config is invalid, using the default: {error}");
+            NetworkConfig::default()
+        }
+    }
+}
+
+pub fn store_network_config(storage: &mut dyn Storage, config: &NetworkConfig) -> Result<()> {
+    store_json(storage, NETWORK_CONFIG_STORAGE_KEY, config)
+}
+
+/// Gives a device without a config token of its own a random one from `random_u32`. The token
+/// is stored and logged once, so it can be read from the serial console of the device. A stored
+/// config that is unreadable or invalid is left as it is rather than replaced by the defaults,
+/// e.g. one written by a newer firmware. Returns whether a token was generated.
+pub fn provision_config_token(
+    storage: &mut dyn Storage,
+    config: &mut NetworkConfig,
+    mut random_u32: impl FnMut() -> u32,
+) -> Result<bool> {
+    if !config.config_token.is_empty() && config.config_token != LEGACY_CONFIG_TOKEN {
+        return Ok(false);
+    }
+    let stored_usable = match load_json::<NetworkConfig>(storage, NETWORK_CONFIG_STORAGE_KEY) {
+        Ok(None) => true,
+        Ok(Some(stored)) => stored.validate().is_ok(),
+        Err(_) => false,
+    };
+    if !stored_usable {
+        warn!("Stored network config is unusable, no config token generated");
+        return Ok(false);
+    }
+    let token: String = (0..CONFIG_TOKEN_BYTES / 4)
+        .map(|_| format!("{:08x}", random_u32()))
+        .collect();
+    let provisioned = NetworkConfig {
+        config_token: token,
+        ..config.clone()
+    };
+    store_network_config(storage, &provisioned)?;
+    info!("Config token generated: {}", provisioned.config_token);
+    *config = provisioned;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn fresh_device_refuses_every_token() {
+        let config = NetworkConfig::default();
+        assert!(config.validate().is_ok());
+        assert!(!config.is_authorized(""));
+        assert!(!config.is_authorized(LEGACY_CONFIG_TOKEN));
+    }
+
+    #[test]
+    fn provisions_a_random_token_once() {
+        let mut storage = MemoryStorage::new();
+        let mut config = load_network_config(&mut storage);
+        let mut next = 0x1234_5678u32;
+        let mut random_u32 = || {
+            next = next.wrapping_mul(7);
+            next
+        };
+        assert!(provision_config_token(&mut storage, &mut config, &mut random_u32).unwrap());
+        assert_eq!(config.config_token.len(), 2 * CONFIG_TOKEN_BYTES);
+        assert!(config.is_authorized(&config.config_token.clone()));
+        assert_eq!(load_network_config(&mut storage), config);
+
+        let token = config.config_token.clone();
+        assert!(!provision_config_token(&mut storage, &mut config, &mut random_u32).unwrap());
+        assert_eq!(config.config_token, token);
+    }
+
+    #[test]
+    fn replaces_the_legacy_token() {
+        let mut storage = MemoryStorage::new();
+        let mut config = NetworkConfig {
+            config_token: LEGACY_CONFIG_TOKEN.to_string(),
+            ..NetworkConfig::default()
+        };
+        assert!(provision_config_token(&mut storage, &mut config, || 1).unwrap());
+        assert_eq!(config.config_token, "00000001".repeat(4));
+        assert!(!config.is_authorized(LEGACY_CONFIG_TOKEN));
+    }
+
+    #[test]
+    fn keeps_an_unusable_stored_config() {
+        let invalid = serde_json::to_vec(&NetworkConfig {
+            wifi_channel: MAX_WIFI_CHANNEL + 1,
+            ..NetworkConfig::default()
+        })
+        .unwrap();
+        for stored in [b"{\"version\":3,\"wifi".to_vec(), invalid] {
+            let mut storage = MemoryStorage::new();
+            storage.store(NETWORK_CONFIG_STORAGE_KEY, &stored).unwrap();
+            let mut config = load_network_config(&mut storage);
+            assert_eq!(config, NetworkConfig::default());
+
+            assert!(!provision_config_token(&mut storage, &mut config, || 1).unwrap());
+            assert!(config.config_token.is_empty());
+            assert_eq!(
+                storage.load(NETWORK_CONFIG_STORAGE_KEY).unwrap(),
+                Some(stored)
+            );
+        }
+    }
+
+    #[test]
+    fn provisions_a_stored_config_without_token() {
+        let mut storage = MemoryStorage::new();
+        let stored = NetworkConfig {
+            station_ssid: "site-network".to_string(),
+            ..NetworkConfig::default()
+        };
+        store_network_config(&mut storage, &stored).unwrap();
+        let mut config = load_network_config(&mut storage);
+        assert!(provision_config_token(&mut storage, &mut config, || 1).unwrap());
+        assert_eq!(
+            load_network_config(&mut storage).station_ssid,
+            "site-network"
+        );
+    }
+
+    #[test]
+    fn refuses_an_empty_token_update() {
+        let config = NetworkConfig {
+            config_token: "secret".to_string(),
+            ..NetworkConfig::default()
+        };
+        let update = NetworkConfigUpdate {
+            config_token: Some(String::new()),
+            ..NetworkConfigUpdate::default()
+        };
+        assert_eq!(
+            config.updated(update),
+            Err(NetworkConfigError::InvalidConfigToken)
+        );
+    }
+}
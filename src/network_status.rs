@@ -0,0 +1,35 @@
+use std::net::Ipv4Addr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mqtt::{Outbox, QoS};
+
+pub const NETWORK_STATUS_TOPIC: &str = "network-status";
+
+/// How the device is on the network. `Mixed` is the fallback when the configured network could
+/// not be joined: the access point is up while the station keeps trying.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum WifiMode {
+    Station,
+    AccessPoint,
+    Mixed,
+}
+
+/// Published retained whenever the Wi-Fi mode, the station connection or the IP changes.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NetworkStatus {
+    pub wifi_mode: WifiMode,
+    pub station_connected: bool,
+    pub ip: Option<Ipv4Addr>,
+}
+
+pub fn queue_network_status(outbox: &Outbox, network_status: &NetworkStatus) {
+    let network_status_json =
+        serde_json::to_vec(network_status).expect("Network status serializes to JSON");
+    outbox.push(
+        NETWORK_STATUS_TOPIC,
+        QoS::AtLeastOnce,
+        true,
+        network_status_json,
+    );
+}
@@ -0,0 +1,449 @@
+use std::{
+    fmt::{self, Display},
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    charging_controller::ChargingControllerState,
+    charging_session::{begin_session, end_session, SessionEndReason},
+    command_response::CommandError,
+    context::Context,
+    controller_status::queue_status,
+    storage::{load_json, store_json, Storage},
+};
+
+/// Storage key of the solar charging settings.
+pub const SOLAR_CHARGING_STORAGE_KEY: &str = "solar_charging";
+
+/// Lowest speed the solar modes charge at.
+const MIN_SOLAR_CHARGING_SPEED_W: u32 = 1;
+/// How long the surplus has to last before solar only charging starts.
+const START_DELAY: Duration = Duration::from_secs(30);
+/// How long the surplus has to be gone before solar only charging stops, so a passing cloud
+/// only slows it down.
+const STOP_DELAY: Duration = Duration::from_secs(120);
+/// Speed changes up to this are ignored, so the speed does not follow measurement noise.
+const SPEED_DEADBAND_W: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SolarMode {
+    #[default]
+    Off,
+    /// Charges only from the surplus.
+    SolarOnly,
+    /// Charges from the surplus, but never slower than `minimum_speed_w`.
+    SolarPlusMinimum,
+}
+
+/// The surplus is the measured solar power minus `margin_w`, which is left for other loads.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SolarSettings {
+    pub mode: SolarMode,
+    pub margin_w: u32,
+    pub minimum_speed_w: u32,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "error", rename_all = "kebab-case")]
+pub enum SolarError {
+    MissingMinimumSpeed,
+}
+
+impl CommandError for SolarError {
+    fn code(&self) -> u16 {
+        match self {
+            SolarError::MissingMinimumSpeed => 1000,
+        }
+    }
+}
+
+impl Display for SolarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolarError::MissingMinimumSpeed => {
+                write!(f, "Solar plus minimum needs a minimum speed")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SolarError {}
+
+impl SolarSettings {
+    pub fn validate(&self) -> Result<(), SolarError> {
+        if self.mode == SolarMode::SolarPlusMinimum && self.minimum_speed_w == 0 {
+            Err(SolarError::MissingMinimumSpeed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How long the surplus has been there or gone, and what solar charging did about it.
+#[derive(Debug, Default)]
+pub struct SolarState {
+    surplus_for: Duration,
+    deficit_for: Duration,
+    /// Set by the manual charging commands until the next car is connected.
+    overridden: bool,
+    /// Solar charging started the charging and may stop it.
+    started_charging: bool,
+}
+
+impl SolarState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn override_solar(&mut self) {
+        self.overridden = true;
+        self.started_charging = false;
+    }
+
+    /// Gives charging back to solar charging, e.g. for a newly connected car.
+    pub fn clear_override(&mut self) {
+        self.overridden = false;
+    }
+
+    pub fn is_overridden(&self) -> bool {
+        self.overridden
+    }
+
+    pub fn started_charging(&self) -> bool {
+        self.started_charging
+    }
+}
+
+/// Loads the stored solar charging settings, solar charging off if none are stored or they are
+/// unusable.
+pub fn load_solar_settings(storage: &mut dyn Storage) -> SolarSettings {
+    let settings = match load_json::<SolarSettings>(storage, SOLAR_CHARGING_STORAGE_KEY) {
+        Ok(settings) => settings.unwrap_or_default(),
+        Err(error) => {
+            warn!("Stored solar charging settings are unreadable, solar charging is off: {error}");
+            SolarSettings::default()
+        }
+    };
+    match settings.validate() {
+        Ok(()) => settings,
+        Err(error) => {
+            warn!("Stored solar charging settings are invalid, solar charging is off: {error}");
+            SolarSettings::default()
+        }
+    }
+}
+
+pub fn store_solar_settings(storage: &mut dyn Storage, settings: &SolarSettings) -> Result<()> {
+    store_json(storage, SOLAR_CHARGING_STORAGE_KEY, settings)
+}
+
+/// Makes the charging speed of a connected car follow the solar surplus of one tick. Solar only
+/// charging starts once the surplus lasted `START_DELAY` and stops once it was gone for
+/// `STOP_DELAY`, in between it charges at the lowest speed. Leaves charging alone while a
+/// departure plan is set or a manual command took over.
+pub fn apply_solar_charging(
+    context: &Context,
+    solar_power_w: f32,
+    elapsed: Duration,
+) -> Result<()> {
+    let mut charging_controller = context
+        .charging_controller_mutex
+        .lock()
+        .expect("Failed lock on charging_controller_mutex");
+    if context
+        .departure_plan_mutex
+        .lock()
+        .expect("Failed lock on departure_plan_mutex")
+        .is_some()
+    {
+        return Ok(());
+    }
+    let settings = *context
+        .solar_settings_rwlock
+        .read()
+        .expect("Failed read access on solar_settings_rwlock");
+    let mut solar_state = context
+        .solar_state_mutex
+        .lock()
+        .expect("Failed lock on solar_state_mutex");
+
+    let surplus_w = solar_power_w - settings.margin_w as f32;
+    if surplus_w >= MIN_SOLAR_CHARGING_SPEED_W as f32 {
+        solar_state.surplus_for += elapsed;
+        solar_state.deficit_for = Duration::ZERO;
+    } else {
+        solar_state.deficit_for += elapsed;
+        solar_state.surplus_for = Duration::ZERO;
+    }
+    if settings.mode == SolarMode::Off || solar_state.overridden {
+        return Ok(());
+    }
+    let Some(car) = charging_controller
+        .car_rwlock()
+        .map(|car_rwlock| *car_rwlock.read().expect("Failed read access on car_rwlock"))
+    else {
+        return Ok(());
+    };
+
+    let surplus_speed_w = surplus_w.max(0.0) as u32;
+    let charging_speed_w = match settings.mode {
+        SolarMode::SolarPlusMinimum => surplus_speed_w.max(settings.minimum_speed_w),
+        _ => surplus_speed_w.max(MIN_SOLAR_CHARGING_SPEED_W),
+    }
+    .min(car.max_charging_speed_w);
+    let solar_only = settings.mode == SolarMode::SolarOnly;
+    let result = match (
+        charging_controller.state(),
+        charging_controller.charging_speed_w(),
+    ) {
+        (ChargingControllerState::Connected, _)
+            if !car.is_fully_charged()
+                && (!solar_only || solar_state.surplus_for >= START_DELAY) =>
+        {
+            info!("Charging with {charging_speed_w}w from the sun");
+            solar_state.started_charging = true;
+            charging_controller
+                .start_charging(charging_speed_w)
+                .map(|()| begin_session(context, &charging_controller))
+        }
+        (ChargingControllerState::Charging, _)
+            if solar_state.started_charging
+                && solar_only
+                && solar_state.deficit_for >= STOP_DELAY =>
+        {
+            info!("Solar surplus gone, charging stopped");
+            solar_state.started_charging = false;
+            charging_controller.stop_charging().map(|()| {
+                end_session(
+                    context,
+                    &charging_controller,
+                    SessionEndReason::SolarSurplusGone,
+                )
+            })
+        }
+        (ChargingControllerState::Charging, Some(current_speed_w))
+            if solar_state.started_charging
+                && current_speed_w.abs_diff(charging_speed_w) > SPEED_DEADBAND_W =>
+        {
+            charging_controller.change_charging_speed(charging_speed_w)
+        }
+        _ => return Ok(()),
+    };
+    if let Err(error) = result {
+        // Not retried every tick, the next car tries again.
+        solar_state.override_solar();
+        Err(error)?
+    }
+    queue_status(context, &charging_controller);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        charging_schedule::TimeOfDay,
+        charging_session::load_sessions,
+        departure_charging::DeparturePlan,
+        handler_functions::{
+            handle_connect_car, handle_disconnect_car, handle_register_car, handle_start_charging,
+            handle_stop_charging,
+        },
+    };
+    use ChargingControllerState::{Charging, Connected};
+    use Step::*;
+
+    /// Seconds between two ticks.
+    const TICK_S: u64 = 10;
+
+    #[derive(Clone, Copy, Debug)]
+    enum Step {
+        /// Ticks for the given seconds with the given solar power.
+        Sun {
+            power_w: f32,
+            for_s: u64,
+        },
+        StartCharging,
+        StopCharging,
+        Reconnect,
+    }
+
+    fn sun(power_w: f32, for_s: u64) -> Step {
+        Sun { power_w, for_s }
+    }
+
+    /// Context with solar charging set to `settings` and an empty 1000 Wh car of at most 50 W
+    /// connected.
+    fn solar_context(settings: SolarSettings) -> Context {
+        let context = Context::in_memory();
+        *context.solar_settings_rwlock.write().unwrap() = settings;
+        handle_register_car(
+            serde_json::from_str(
+                r#"{"car_id":"van","charging_capacity_wh":1000,"current_charge_wh":0,"max_charging_speed_w":50}"#,
+            )
+            .unwrap(),
+            context.clone(),
+        )
+        .unwrap();
+        handle_connect_car(
+            serde_json::from_str(r#"{"car_id":"van"}"#).unwrap(),
+            context.clone(),
+        )
+        .unwrap();
+        context
+    }
+
+    /// Runs each step and checks the charging state and speed after it.
+    fn run(context: &Context, steps: &[(Step, ChargingControllerState, Option<u32>)]) {
+        for (index, (step, state, charging_speed_w)) in steps.iter().enumerate() {
+            match *step {
+                Sun { power_w, for_s } => {
+                    for _ in 0..for_s / TICK_S {
+                        apply_solar_charging(context, power_w, Duration::from_secs(TICK_S))
+                            .unwrap();
+                    }
+                }
+                StartCharging => handle_start_charging(
+                    serde_json::from_str(r#"{"charging_speed_w":20}"#).unwrap(),
+                    context.clone(),
+                )
+                .unwrap(),
+                StopCharging => handle_stop_charging(context.clone()).unwrap(),
+                Reconnect => {
+                    handle_disconnect_car(context.clone()).unwrap();
+                    handle_connect_car(
+                        serde_json::from_str(r#"{"car_id":"van"}"#).unwrap(),
+                        context.clone(),
+                    )
+                    .unwrap();
+                }
+            }
+            let charging_controller = context.charging_controller_mutex.lock().unwrap();
+            assert_eq!(
+                (
+                    charging_controller.state(),
+                    charging_controller.charging_speed_w()
+                ),
+                (*state, *charging_speed_w),
+                "step {index}: {step:?}"
+            );
+        }
+    }
+
+    fn solar_only() -> SolarSettings {
+        SolarSettings {
+            mode: SolarMode::SolarOnly,
+            margin_w: 2,
+            minimum_speed_w: 0,
+        }
+    }
+
+    #[test]
+    fn solar_only_starts_after_surplus_lasted() {
+        let context = solar_context(solar_only());
+        run(
+            &context,
+            &[
+                (sun(12.0, 20), Connected, None),
+                // A gap restarts the wait.
+                (sun(2.5, 10), Connected, None),
+                (sun(12.0, 20), Connected, None),
+                (sun(12.0, 10), Charging, Some(10)),
+                (sun(100.0, 10), Charging, Some(50)),
+            ],
+        );
+    }
+
+    #[test]
+    fn solar_only_stops_after_surplus_was_gone() {
+        let context = solar_context(solar_only());
+        run(
+            &context,
+            &[
+                (sun(12.0, 30), Charging, Some(10)),
+                // A passing cloud only slows it down.
+                (sun(0.0, 110), Charging, Some(1)),
+                (sun(12.0, 10), Charging, Some(10)),
+                (sun(0.0, 110), Charging, Some(1)),
+                (sun(0.0, 10), Connected, None),
+            ],
+        );
+        let sessions = load_sessions(&mut *context.storage_mutex.lock().unwrap(), 1).unwrap();
+        assert_eq!(sessions[0].end_reason, SessionEndReason::SolarSurplusGone);
+    }
+
+    #[test]
+    fn ignores_speed_changes_within_deadband() {
+        let context = solar_context(solar_only());
+        run(
+            &context,
+            &[
+                (sun(12.0, 30), Charging, Some(10)),
+                (sun(13.0, 10), Charging, Some(10)),
+                (sun(11.0, 10), Charging, Some(10)),
+                (sun(14.0, 10), Charging, Some(12)),
+                (sun(15.0, 10), Charging, Some(12)),
+                (sun(11.0, 10), Charging, Some(9)),
+            ],
+        );
+    }
+
+    #[test]
+    fn solar_plus_minimum_never_drops_below_minimum() {
+        let context = solar_context(SolarSettings {
+            mode: SolarMode::SolarPlusMinimum,
+            margin_w: 2,
+            minimum_speed_w: 4,
+        });
+        run(
+            &context,
+            &[
+                (sun(0.0, 10), Charging, Some(4)),
+                (sun(20.0, 10), Charging, Some(18)),
+                (sun(0.0, 600), Charging, Some(4)),
+                (sun(100.0, 10), Charging, Some(50)),
+            ],
+        );
+    }
+
+    #[test]
+    fn manual_commands_take_over_until_reconnect() {
+        let context = solar_context(solar_only());
+        run(
+            &context,
+            &[
+                (sun(12.0, 30), Charging, Some(10)),
+                (StopCharging, Connected, None),
+                (sun(12.0, 60), Connected, None),
+                (StartCharging, Charging, Some(20)),
+                (sun(0.0, 300), Charging, Some(20)),
+                (StopCharging, Connected, None),
+                (Reconnect, Connected, None),
+                (sun(12.0, 30), Charging, Some(10)),
+            ],
+        );
+    }
+
+    #[test]
+    fn departure_plan_wins() {
+        let context = solar_context(solar_only());
+        *context.departure_plan_mutex.lock().unwrap() =
+            Some(DeparturePlan::new(900, TimeOfDay::new(7, 30).unwrap()));
+        run(&context, &[(sun(12.0, 60), Connected, None)]);
+        // Neither was the surplus counted while the plan was set.
+        *context.departure_plan_mutex.lock().unwrap() = None;
+        run(
+            &context,
+            &[
+                (sun(12.0, 20), Connected, None),
+                (sun(12.0, 10), Charging, Some(10)),
+            ],
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Longest key the NVS partition accepts.
+pub const MAX_KEY_LENGTH: usize = 15;
+
+/// Persistent key-value store for settings that have to survive a reboot. Backed by the NVS
+/// partition on the device and by memory on the host. Keys are at most `MAX_KEY_LENGTH` characters
+/// long.
+pub trait Storage: Send {
+    fn load(&mut self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn store(&mut self, key: &str, value: &[u8]) -> Result<()>;
+    fn remove(&mut self, key: &str) -> Result<()>;
+}
+
+pub fn load_json<T: DeserializeOwned>(storage: &mut dyn Storage, key: &str) -> Result<Option<T>> {
+    match storage.load(key)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn store_json<T: Serialize>(storage: &mut dyn Storage, key: &str, value: &T) -> Result<()> {
+    storage.store(key, &serde_json::to_vec(value)?)
+}
+
+/// Storage kept in memory. Clones share their entries.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    entries: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .entries
+            .lock()
+            .expect("Failed lock on memory storage")
+            .get(key)
+            .cloned())
+    }
+
+    fn store(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.entries
+            .lock()
+            .expect("Failed lock on memory storage")
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.entries
+            .lock()
+            .expect("Failed lock on memory storage")
+            .remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "esp")]
+pub use nvs_storage::NvsStorage;
+
+#[cfg(feature = "esp")]
+mod nvs_storage {
+    use anyhow::Result;
+    use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+    use super::Storage;
+
+    const NVS_NAMESPACE: &str = "smacha";
+
+    pub struct NvsStorage {
+        nvs: EspNvs<NvsDefault>,
+    }
+
+    impl NvsStorage {
+        pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+            Ok(NvsStorage {
+                nvs: EspNvs::new(partition, NVS_NAMESPACE, true)?,
+            })
+        }
+    }
+
+    impl Storage for NvsStorage {
+        fn load(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+            let Some(length) = self.nvs.blob_len(key)? else {
+                return Ok(None);
+            };
+            let mut buffer = vec![0u8; length];
+            Ok(self.nvs.get_blob(key, &mut buffer)?.map(<[u8]>::to_vec))
+        }
+
+        fn store(&mut self, key: &str, value: &[u8]) -> Result<()> {
+            self.nvs.set_blob(key, value)?;
+            Ok(())
+        }
+
+        fn remove(&mut self, key: &str) -> Result<()> {
+            self.nvs.remove(key)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MAX_KEY_LENGTH;
+    use crate::{
+        calibration::CALIBRATION_STORAGE_KEY,
+        car_registry::CARS_STORAGE_KEY,
+        charging_schedule::CHARGING_SCHEDULE_STORAGE_KEY,
+        charging_session::{session_storage_key, NEXT_SESSION_STORAGE_KEY, SESSION_SLOTS},
+        network_config::NETWORK_CONFIG_STORAGE_KEY,
+        solar_charging::SOLAR_CHARGING_STORAGE_KEY,
+    };
+
+    #[test]
+    fn storage_keys_fit_nvs() {
+        let keys = [
+            CALIBRATION_STORAGE_KEY.to_string(),
+            CARS_STORAGE_KEY.to_string(),
+            CHARGING_SCHEDULE_STORAGE_KEY.to_string(),
+            NETWORK_CONFIG_STORAGE_KEY.to_string(),
+            NEXT_SESSION_STORAGE_KEY.to_string(),
+            SOLAR_CHARGING_STORAGE_KEY.to_string(),
+            session_storage_key(SESSION_SLOTS - 1),
+        ];
+        for key in keys {
+            assert!(key.len() <= MAX_KEY_LENGTH, "Storage key {key} is too long");
+        }
+    }
+}
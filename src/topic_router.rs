@@ -0,0 +1,260 @@
+use std::io::{Error, ErrorKind};
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+
+use crate::{context::Context, mqtt::QoS};
+
+/// Prefix of the command topics unless a device sets its own.
+pub const DEFAULT_TOPIC_PREFIX: &str = "/charging-controller";
+
+/// Topic prefix of the device with `device_id`, so several devices can share a broker.
+pub fn device_topic_prefix(device_id: &str) -> String {
+    format!("{DEFAULT_TOPIC_PREFIX}/{device_id}")
+}
+
+type CommandHandler = Box<dyn Fn(&[u8], Context) -> Result<()> + Send + Sync>;
+
+/// A command topic, relative to the router's prefix, with the QoS it is subscribed with and the
+/// handler its payload is parsed for.
+pub struct Route {
+    command: &'static str,
+    qos: QoS,
+    handler: CommandHandler,
+}
+
+impl Route {
+    /// Route whose JSON payload is parsed into `T` before it reaches the handler.
+    pub fn new<T: DeserializeOwned + 'static>(
+        command: &'static str,
+        qos: QoS,
+        handler: fn(T, Context) -> Result<()>,
+    ) -> Self {
+        Route {
+            command,
+            qos,
+            handler: Box::new(move |data, context| handler(serde_json::from_slice(data)?, context)),
+        }
+    }
+
+    /// Route whose JSON payload is parsed into `T`, with an empty payload standing for
+    /// `T::default()`.
+    pub fn with_optional_payload<T: DeserializeOwned + Default + 'static>(
+        command: &'static str,
+        qos: QoS,
+        handler: fn(T, Context) -> Result<()>,
+    ) -> Self {
+        Route {
+            command,
+            qos,
+            handler: Box::new(move |data, context| {
+                let data = if data.trim_ascii().is_empty() {
+                    T::default()
+                } else {
+                    serde_json::from_slice(data)?
+                };
+                handler(data, context)
+            }),
+        }
+    }
+
+    /// Route whose payload is ignored.
+    pub fn without_payload(
+        command: &'static str,
+        qos: QoS,
+        handler: fn(Context) -> Result<()>,
+    ) -> Self {
+        Route {
+            command,
+            qos,
+            handler: Box::new(move |_data, context| handler(context)),
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        self.command
+    }
+
+    pub fn qos(&self) -> QoS {
+        self.qos
+    }
+}
+
+/// Registry of the command topics. Subscription and dispatch both come from it, so a command
+/// only has to be added in one place.
+pub struct TopicRouter {
+    prefix: String,
+    routes: Vec<Route>,
+}
+
+impl TopicRouter {
+    pub fn new(prefix: &str) -> Self {
+        TopicRouter {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn route(mut self, route: Route) -> Self {
+        assert!(
+            self.routes
+                .iter()
+                .all(|other| other.command != route.command),
+            "Command {} is routed twice",
+            route.command
+        );
+        self.routes.push(route);
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    pub fn topic(&self, route: &Route) -> String {
+        format!("{}/{}", self.prefix, route.command)
+    }
+
+    /// Topics to subscribe to, with their QoS.
+    pub fn subscriptions(&self) -> impl Iterator<Item = (String, QoS)> + '_ {
+        self.routes
+            .iter()
+            .map(|route| (self.topic(route), route.qos))
+    }
+
+    pub fn dispatch(&self, topic: &str, data: &[u8], context: Context) -> Result<()> {
+        let route = topic
+            .strip_prefix(self.prefix.as_str())
+            .and_then(|command| command.strip_prefix('/'))
+            .and_then(|command| self.routes.iter().find(|route| route.command == command));
+        match route {
+            Some(route) => (route.handler)(data, context),
+            None => {
+                let message = format!("Topic: {topic} not available");
+                Err(Error::new(ErrorKind::InvalidData, message))?
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize, Default)]
+    struct Flag {
+        set: bool,
+    }
+
+    fn set_flag(flag: Flag, context: Context) -> Result<()> {
+        context
+            .calibration_requested
+            .store(flag.set, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn raise_flag(context: Context) -> Result<()> {
+        context.calibration_requested.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn router() -> TopicRouter {
+        TopicRouter::new("/devices/a1/")
+            .route(Route::new("set-flag", QoS::AtLeastOnce, set_flag))
+            .route(Route::without_payload(
+                "raise-flag",
+                QoS::AtMostOnce,
+                raise_flag,
+            ))
+            .route(Route::with_optional_payload(
+                "maybe-set-flag",
+                QoS::AtMostOnce,
+                set_flag,
+            ))
+    }
+
+    #[test]
+    fn subscribes_below_prefix() {
+        let subscriptions: Vec<(String, QoS)> = router().subscriptions().collect();
+        assert_eq!(
+            subscriptions,
+            [
+                ("/devices/a1/set-flag".to_string(), QoS::AtLeastOnce),
+                ("/devices/a1/raise-flag".to_string(), QoS::AtMostOnce),
+                ("/devices/a1/maybe-set-flag".to_string(), QoS::AtMostOnce),
+            ]
+        );
+    }
+
+    #[test]
+    fn dispatches_to_route() {
+        let router = router();
+        let context = Context::in_memory();
+        router
+            .dispatch("/devices/a1/set-flag", br#"{"set":true}"#, context.clone())
+            .unwrap();
+        assert!(context.calibration_requested.load(Ordering::SeqCst));
+        router
+            .dispatch("/devices/a1/set-flag", br#"{"set":false}"#, context.clone())
+            .unwrap();
+        assert!(!context.calibration_requested.load(Ordering::SeqCst));
+        router
+            .dispatch("/devices/a1/raise-flag", b"", context.clone())
+            .unwrap();
+        assert!(context.calibration_requested.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn defaults_empty_optional_payload() {
+        let router = router();
+        let context = Context::in_memory();
+        router
+            .dispatch(
+                "/devices/a1/maybe-set-flag",
+                br#"{"set":true}"#,
+                context.clone(),
+            )
+            .unwrap();
+        assert!(context.calibration_requested.load(Ordering::SeqCst));
+        router
+            .dispatch("/devices/a1/maybe-set-flag", b"", context.clone())
+            .unwrap();
+        assert!(!context.calibration_requested.load(Ordering::SeqCst));
+        assert!(router
+            .dispatch("/devices/a1/maybe-set-flag", b"{", context.clone())
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_topics_and_payloads() {
+        let router = router();
+        let context = Context::in_memory();
+        for topic in [
+            "/devices/a1/lower-flag",
+            "/devices/a2/set-flag",
+            "/devices/a1set-flag",
+        ] {
+            assert!(router
+                .dispatch(topic, br#"{"set":true}"#, context.clone())
+                .is_err());
+        }
+        assert!(router
+            .dispatch("/devices/a1/set-flag", b"", context.clone())
+            .is_err());
+        assert!(!context.calibration_requested.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[should_panic(expected = "Command set-flag is routed twice")]
+    fn refuses_duplicate_routes() {
+        router().route(Route::new("set-flag", QoS::AtMostOnce, set_flag));
+    }
+}
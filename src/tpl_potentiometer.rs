@@ -1,5 +1,56 @@
+use std::fmt::{self, Debug, Display};
+
 use embedded_hal::blocking::i2c;
-use log::error;
+
+/// Command byte addressing the wiper register.
+const WIPER_REGISTER: u8 = 0x00;
+pub const MAX_WIPER_STEP: u8 = 127;
+pub const MAX_RESISTANCE_KOHM: f32 = 10.0;
+/// Wiper steps per kOhm.
+const STEPS_PER_KOHM: f32 = MAX_WIPER_STEP as f32 / MAX_RESISTANCE_KOHM;
+
+#[derive(Debug, PartialEq)]
+pub enum TPLPotentiometerError<E> {
+    ResistanceOutOfRange { kohm: f32 },
+    WiperStepOutOfRange { step: u8 },
+    ReadBackMismatch { written: u8, read: u8 },
+    Bus(E),
+}
+
+impl<E: Debug> Display for TPLPotentiometerError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TPLPotentiometerError::ResistanceOutOfRange { kohm } => write!(
+                f,
+                "Resistance value must be within 0 to {MAX_RESISTANCE_KOHM} kOhm, given: {kohm}kOhm"
+            ),
+            TPLPotentiometerError::WiperStepOutOfRange { step } => write!(
+                f,
+                "Wiper step must be within 0 to {MAX_WIPER_STEP}, given: {step}"
+            ),
+            TPLPotentiometerError::ReadBackMismatch { written, read } => {
+                write!(f, "Wiper step read back as {read} after writing {written}")
+            }
+            TPLPotentiometerError::Bus(error) => write!(f, "I2C bus error: {error:?}"),
+        }
+    }
+}
+
+impl<E: Debug> std::error::Error for TPLPotentiometerError<E> {}
+
+/// Converts a resistance into the closest wiper step. Step 127 is 0 kOhm, step 0 is 10 kOhm.
+pub fn resistance_to_wiper_step<E>(kohm: f32) -> Result<u8, TPLPotentiometerError<E>> {
+    if !(0.0..=MAX_RESISTANCE_KOHM).contains(&kohm) {
+        Err(TPLPotentiometerError::ResistanceOutOfRange { kohm })
+    } else {
+        Ok(MAX_WIPER_STEP - (kohm * STEPS_PER_KOHM).round() as u8)
+    }
+}
+
+pub fn wiper_step_to_resistance(step: u8) -> f32 {
+    (MAX_WIPER_STEP - step.min(MAX_WIPER_STEP)) as f32 / STEPS_PER_KOHM
+}
+
 #[derive(Clone)]
 pub struct TPLPotentiometer<I2C> {
     i2c: I2C,
@@ -14,17 +65,134 @@ where
         TPLPotentiometer { i2c, address }
     }
 
-    pub fn set_resistance(&mut self, kohm: f32) -> Result<(), E> {
-        if kohm < 10.0 || kohm > 0.0 {
-            error!(
-                "Resistance value must be within 0 to 10 kOhm, given: {}kOhm",
-                kohm
-            );
-        } else {
-            let scaled_kohm = (kohm * 12.7) as u8;
-            let register_value = 127 - scaled_kohm;
-            self.i2c.write(self.address, &[register_value])?;
+    pub fn set_wiper_step(&mut self, step: u8) -> Result<(), TPLPotentiometerError<E>> {
+        if step > MAX_WIPER_STEP {
+            Err(TPLPotentiometerError::WiperStepOutOfRange { step })?
+        }
+        self.i2c
+            .write(self.address, &[WIPER_REGISTER, step])
+            .map_err(TPLPotentiometerError::Bus)
+    }
+
+    pub fn wiper_step(&mut self) -> Result<u8, TPLPotentiometerError<E>> {
+        let mut buffer = [0u8; 1];
+        self.i2c
+            .write(self.address, &[WIPER_REGISTER])
+            .map_err(TPLPotentiometerError::Bus)?;
+        self.i2c
+            .read(self.address, &mut buffer)
+            .map_err(TPLPotentiometerError::Bus)?;
+        Ok(buffer[0])
+    }
+
+    /// Writes the wiper step and reads it back to confirm the device took it.
+    pub fn set_wiper_step_verified(&mut self, step: u8) -> Result<(), TPLPotentiometerError<E>> {
+        self.set_wiper_step(step)?;
+        let read = self.wiper_step()?;
+        if read != step {
+            Err(TPLPotentiometerError::ReadBackMismatch {
+                written: step,
+                read,
+            })?
         }
         Ok(())
     }
+
+    pub fn set_resistance(&mut self, kohm: f32) -> Result<(), TPLPotentiometerError<E>> {
+        self.set_wiper_step(resistance_to_wiper_step(kohm)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        i2c::TPL_ADDRESS,
+        i2c_simulator::{SimulatedI2CBus, SimulatedI2CError, SimulatedTPLPotentiometer},
+    };
+
+    type Error = TPLPotentiometerError<SimulatedI2CError>;
+
+    fn simulated_potentiometer() -> (TPLPotentiometer<SimulatedI2CBus>, SimulatedTPLPotentiometer) {
+        let bus = SimulatedI2CBus::new();
+        let simulated_potentiometer = SimulatedTPLPotentiometer::new();
+        bus.attach(TPL_ADDRESS, simulated_potentiometer.clone());
+        (
+            TPLPotentiometer::new(bus, TPL_ADDRESS),
+            simulated_potentiometer,
+        )
+    }
+
+    #[test]
+    fn converts_resistance_to_closest_step() {
+        for (kohm, step) in [(0.0, 127), (10.0, 0), (5.0, 63), (0.03, 127), (0.05, 126)] {
+            assert_eq!(resistance_to_wiper_step::<()>(kohm), Ok(step), "{kohm}kOhm");
+        }
+        for (step, kohm) in [(127, 0.0), (0, 10.0), (255, 0.0)] {
+            assert_eq!(wiper_step_to_resistance(step), kohm, "step {step}");
+        }
+        for step in 0..=MAX_WIPER_STEP {
+            assert_eq!(
+                resistance_to_wiper_step::<()>(wiper_step_to_resistance(step)),
+                Ok(step)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let (mut potentiometer, simulated_potentiometer) = simulated_potentiometer();
+        assert_eq!(
+            potentiometer.set_wiper_step(128),
+            Err(Error::WiperStepOutOfRange { step: 128 })
+        );
+        for kohm in [-0.1, 10.1, f32::INFINITY] {
+            assert_eq!(
+                potentiometer.set_resistance(kohm),
+                Err(Error::ResistanceOutOfRange { kohm })
+            );
+        }
+        assert!(resistance_to_wiper_step::<()>(f32::NAN).is_err());
+        assert!(simulated_potentiometer.writes().is_empty());
+    }
+
+    #[test]
+    fn writes_command_and_step() {
+        let (mut potentiometer, simulated_potentiometer) = simulated_potentiometer();
+        potentiometer.set_wiper_step(42).unwrap();
+        potentiometer.set_resistance(0.0).unwrap();
+        potentiometer.set_wiper_step_verified(7).unwrap();
+        assert_eq!(simulated_potentiometer.writes(), [42, 127, 7]);
+        assert_eq!(simulated_potentiometer.wiper_step(), 7);
+        assert_eq!(potentiometer.wiper_step(), Ok(7));
+    }
+
+    #[test]
+    fn reports_step_not_taken() {
+        let (mut potentiometer, simulated_potentiometer) = simulated_potentiometer();
+        simulated_potentiometer.set_stuck(true);
+        assert_eq!(
+            potentiometer.set_wiper_step_verified(42),
+            Err(Error::ReadBackMismatch {
+                written: 42,
+                read: 0x40,
+            })
+        );
+        assert_eq!(simulated_potentiometer.writes(), [42]);
+    }
+
+    #[test]
+    fn wraps_bus_errors() {
+        let (mut potentiometer, simulated_potentiometer) = simulated_potentiometer();
+        simulated_potentiometer.set_nack(true);
+        let nack = SimulatedI2CError::Nack {
+            address: TPL_ADDRESS,
+        };
+        assert_eq!(potentiometer.set_wiper_step(42), Err(Error::Bus(nack)));
+        assert_eq!(potentiometer.wiper_step(), Err(Error::Bus(nack)));
+        assert_eq!(
+            potentiometer.set_wiper_step_verified(42),
+            Err(Error::Bus(nack))
+        );
+    }
 }
@@ -0,0 +1,187 @@
+use std::fmt::Debug;
+
+use anyhow::Result;
+use embedded_hal::{blocking::i2c, digital::v2::OutputPin};
+use log::{error, info};
+
+use crate::{
+    async_timer::AsyncTimer,
+    charging_error::ChargingError,
+    context::Context,
+    controller_event::{publish_event, ControllerEvent},
+    controller_status::queue_status,
+    hardware_controller::HardwareController,
+    mqtt::MqttPublisher,
+};
+
+/// Trips are requested by the start-trip command, which also puts the charging controller into
+/// `Driving`, and run by the trip task, since a trip keeps the motor running far longer than a
+/// command handler may block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TripState {
+    Idle,
+    Requested { energy_usage_w: u32 },
+    Running { energy_usage_w: u32 },
+}
+
+/// Runs the requested trip, if any, and takes its energy off the car once the motor stopped.
+pub async fn run_requested_trip<I2C, E, P>(
+    context: &Context,
+    hardware_controller: &HardwareController<I2C, P>,
+    timer: &mut impl AsyncTimer,
+    mqtt_client: &mut impl MqttPublisher,
+) -> Result<()>
+where
+    I2C: i2c::Write<Error = E> + i2c::Read<Error = E>,
+    E: Debug,
+    P: OutputPin,
+    P::Error: Debug,
+{
+    let energy_usage_w = {
+        let mut trip_state = context
+            .trip_state_mutex
+            .lock()
+            .expect("Failed lock on trip_state_mutex");
+        match *trip_state {
+            TripState::Requested { energy_usage_w } => {
+                *trip_state = TripState::Running { energy_usage_w };
+                energy_usage_w
+            }
+            _ => return Ok(()),
+        }
+    };
+    // The controller has to leave `Driving` again, so the trip goes on without the event.
+    if let Err(error) = publish_event(
+        mqtt_client,
+        &ControllerEvent::TripStarted { energy_usage_w },
+    )
+    .await
+    {
+        error!("Failed to publish trip start: {error}");
+    }
+
+    let trip_result = hardware_controller.start_trip(timer, energy_usage_w).await;
+    *context
+        .trip_state_mutex
+        .lock()
+        .expect("Failed lock on trip_state_mutex") = TripState::Idle;
+    let car_rwlock = {
+        let mut charging_controller = context
+            .charging_controller_mutex
+            .lock()
+            .expect("Failed lock on charging_controller_mutex");
+        let car_rwlock = charging_controller
+            .car_rwlock()
+            .ok_or(ChargingError::NotDriving)?
+            .clone();
+        charging_controller.stop_driving()?;
+        if trip_result.is_err() {
+            queue_status(context, &charging_controller);
+        }
+        car_rwlock
+    };
+    if let Err(error) = trip_result {
+        if let Err(publish_error) =
+            publish_event(mqtt_client, &ControllerEvent::TripFailed { energy_usage_w }).await
+        {
+            error!("Failed to publish trip failure: {publish_error}");
+        }
+        return Err(error);
+    }
+
+    let current_charge_wh = {
+        let mut car = car_rwlock
+            .write()
+            .expect("Failed write access on car_rwlock");
+        let new_charge_wh = car.current_charge_wh().saturating_sub(energy_usage_w);
+        car.change_current_charge(new_charge_wh)?;
+        new_charge_wh
+    };
+    queue_status(
+        context,
+        &context
+            .charging_controller_mutex
+            .lock()
+            .expect("Failed lock on charging_controller_mutex"),
+    );
+    context
+        .car_registry_rwlock
+        .read()
+        .expect("Failed read access on car_registry_rwlock")
+        .store(
+            &mut *context
+                .storage_mutex
+                .lock()
+                .expect("Failed lock on storage_mutex"),
+        )?;
+    info!("Trip finished, car charge is {current_charge_wh}wh");
+    publish_event(
+        mqtt_client,
+        &ControllerEvent::TripFinished {
+            energy_usage_w,
+            current_charge_wh,
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::{
+        car::Car,
+        charging_controller::ChargingControllerState,
+        i2c::TPL_ADDRESS,
+        i2c_simulator::SimulatedI2CBus,
+        mock_hardware::{MockClock, MockTimer, PinLevel, RecordingOutputPin},
+        mqtt::QoS,
+        tpl_potentiometer::TPLPotentiometer,
+    };
+
+    struct FailingPublisher;
+
+    impl MqttPublisher for FailingPublisher {
+        async fn publish(&mut self, _: &str, _: QoS, _: bool, _: &[u8]) -> Result<()> {
+            Err(anyhow!("Broker unreachable"))
+        }
+    }
+
+    #[test]
+    fn trip_ends_when_events_cannot_be_published() {
+        let context = Context::in_memory();
+        let car_rwlock = Arc::new(RwLock::new(Car::new(1000, 500, 100).unwrap()));
+        context
+            .charging_controller_mutex
+            .lock()
+            .unwrap()
+            .start_driving(car_rwlock.clone())
+            .unwrap();
+        *context.trip_state_mutex.lock().unwrap() = TripState::Requested { energy_usage_w: 2 };
+        let clock = MockClock::new();
+        let trip_motor_pin = RecordingOutputPin::new(clock.clone());
+        let hardware_controller = HardwareController::new(
+            TPLPotentiometer::new(SimulatedI2CBus::new(), TPL_ADDRESS),
+            trip_motor_pin.clone(),
+            Default::default(),
+        );
+
+        let result = embassy_futures::block_on(run_requested_trip(
+            &context,
+            &hardware_controller,
+            &mut MockTimer::new(clock),
+            &mut FailingPublisher,
+        ));
+        assert!(result.is_err());
+        assert_eq!(trip_motor_pin.level(), PinLevel::Low);
+        assert_eq!(
+            context.charging_controller_mutex.lock().unwrap().state(),
+            ChargingControllerState::Disconnected
+        );
+        assert_eq!(*context.trip_state_mutex.lock().unwrap(), TripState::Idle);
+        assert_eq!(car_rwlock.read().unwrap().current_charge_wh(), 498);
+    }
+}
@@ -0,0 +1,13 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The clock starts at the epoch on boot, anything before this has not been set by SNTP yet.
+const MIN_SYNCED_UNIX_TIME: Duration = Duration::from_secs(1_704_067_200);
+
+/// Seconds since the Unix epoch, once SNTP has set the clock.
+pub fn unix_time_s() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .filter(|unix_time| *unix_time >= MIN_SYNCED_UNIX_TIME)
+        .map(|unix_time| unix_time.as_secs())
+}